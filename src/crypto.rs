@@ -3,14 +3,224 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::aead::{Aead, NewAead, Payload};
 use aes_gcm::{Aes256Gcm, Key, Nonce};
 use anyhow::*;
+use log::warn;
 use rand::Rng;
+use std::collections::HashMap;
+use std::env;
+
+use crate::db;
+
+extern crate lazy_static;
 
 const CONNECTION_IV_LENGTH: usize = 12;
+// AES-GCM authentication tag appended to every wrapped blob.
+const AES_GCM_TAG_LENGTH: usize = 16;
+
+lazy_static::lazy_static! {
+    // Process-stable fallback key used when KBS_MASTER_KEY is unset. Minting it
+    // once per process (rather than once per `from_env` call) lets a row wrapped
+    // by one KbsDb instance be unwrapped by another within the same run; it is
+    // still lost across a restart, which is why deployments must set the env var.
+    static ref EPHEMERAL_MASTER_KEY: Vec<u8> =
+        rand::thread_rng().gen::<[u8; MASTER_KEY_LENGTH]>().to_vec();
+}
+
+// Outcome of unwrapping a stored column that may predate at-rest wrapping.
+pub enum MaybeWrapped {
+    // The column was a versioned AEAD blob that decrypted to this plaintext.
+    Plaintext(Vec<u8>),
+    // The column is not a blob this keyring produced (a pre-wrap legacy row);
+    // the caller should decode it with the legacy path.
+    Legacy,
+}
+
+// At-rest wrapping key material. Columns holding key material (`secret`,
+// `symkey`, `keypair`) are wrapped with AES-256-GCM under a master key before
+// they are bound into a query, so a raw database dump leaks only ciphertext.
+const MASTER_KEY_LENGTH: usize = 32;
+
+// Env var holding the current master key: a base64 32-byte key, or a URL
+// resolved through `resolve_master_key` (e.g. `file:///path/to/key`).
+const MASTER_KEY_ENV: &str = "KBS_MASTER_KEY";
+// Version byte advertised for newly wrapped blobs; defaults to 1.
+const MASTER_KEY_VERSION_ENV: &str = "KBS_MASTER_KEY_VERSION";
+// Previous keys retained for lazy rotation, as `version:base64,version:base64`.
+const MASTER_KEY_PREVIOUS_ENV: &str = "KBS_MASTER_KEY_PREVIOUS";
+
+// A set of versioned master keys. The current version wraps new blobs; any
+// retained previous version can still unwrap old rows, so the master key can
+// be rotated and rows re-wrapped lazily as they are read and written back.
+#[derive(Clone)]
+pub struct MasterKeyring {
+    keys: HashMap<u8, Vec<u8>>,
+    current_version: u8,
+}
+
+impl MasterKeyring {
+    // Load the keyring from the environment. `KBS_MASTER_KEY` is either base64
+    // key material or a URL understood by `resolve_master_key`;
+    // `KBS_MASTER_KEY_VERSION` selects the version it is registered under
+    // (default 1); `KBS_MASTER_KEY_PREVIOUS` carries retired keys so rows
+    // wrapped before a rotation stay readable. When `KBS_MASTER_KEY` is unset
+    // we fall back to a process-ephemeral key so dev runs and the test suite
+    // still work; deployments MUST set it to persist wrapped rows across
+    // restarts.
+    pub fn from_env() -> Result<Self> {
+        let current_version = match env::var(MASTER_KEY_VERSION_ENV) {
+            Ok(v) => v.parse::<u8>()?,
+            Err(_) => 1,
+        };
+
+        let mut keys = HashMap::new();
+        match env::var(MASTER_KEY_ENV) {
+            Ok(spec) => {
+                keys.insert(current_version, resolve_master_key(&spec)?);
+            }
+            Err(_) => {
+                warn!(
+                    "{} not set; using an ephemeral master key — wrapped rows will not survive a restart",
+                    MASTER_KEY_ENV
+                );
+                keys.insert(current_version, EPHEMERAL_MASTER_KEY.clone());
+            }
+        }
+
+        if let Ok(previous) = env::var(MASTER_KEY_PREVIOUS_ENV) {
+            for entry in previous.split(',').filter(|e| !e.is_empty()) {
+                let (version, material) = entry
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("malformed {} entry: {}", MASTER_KEY_PREVIOUS_ENV, entry))?;
+                keys.insert(version.parse::<u8>()?, resolve_master_key(material)?);
+            }
+        }
+
+        Ok(MasterKeyring {
+            keys,
+            current_version,
+        })
+    }
+
+    fn key(&self, version: u8) -> Result<&[u8]> {
+        self.keys
+            .get(&version)
+            .map(|k| k.as_slice())
+            .ok_or_else(|| anyhow!("no master key for version {}", version))
+    }
+
+    // Wrap `plaintext` into a versioned blob `key_version || nonce ||
+    // ciphertext || tag`, base64-encoded for storage in a text column.
+    pub fn wrap(&self, plaintext: &[u8]) -> Result<String> {
+        let k = Key::from_slice(self.key(self.current_version)?);
+        let cipher = Aes256Gcm::new(k);
+
+        let iv = rand::thread_rng().gen::<[u8; CONNECTION_IV_LENGTH]>();
+        let nonce = Nonce::from_slice(&iv);
+        let ciphertext = cipher
+            .encrypt(nonce, plaintext)
+            .map_err(|e| anyhow!("at-rest wrap error: {}", e))?;
+
+        let mut blob = Vec::with_capacity(1 + CONNECTION_IV_LENGTH + ciphertext.len());
+        blob.push(self.current_version);
+        blob.extend_from_slice(&iv);
+        blob.extend_from_slice(&ciphertext);
+        Ok(base64::encode(blob))
+    }
+
+    // Reverse `wrap`, selecting the key named by the blob's version byte.
+    pub fn unwrap(&self, blob: &str) -> Result<Vec<u8>> {
+        let blob = base64::decode(blob)?;
+        if blob.len() < 1 + CONNECTION_IV_LENGTH {
+            bail!("truncated at-rest blob");
+        }
+        let version = blob[0];
+        let iv = &blob[1..1 + CONNECTION_IV_LENGTH];
+        let ciphertext = &blob[1 + CONNECTION_IV_LENGTH..];
+
+        let k = Key::from_slice(self.key(version)?);
+        let cipher = Aes256Gcm::new(k);
+        let nonce = Nonce::from_slice(iv);
+        cipher
+            .decrypt(nonce, ciphertext)
+            .map_err(|e| anyhow!("at-rest unwrap error: {}", e))
+    }
+
+    // Unwrap a stored column that may predate at-rest wrapping, distinguishing a
+    // value that was never wrapped from a well-formed blob that failed to
+    // decrypt. A column counts as a wrapped blob only if it base64-decodes to at
+    // least a version byte, nonce and tag AND its version byte names a key this
+    // keyring holds; such a blob's decrypt failure is a hard error, because
+    // returning its ciphertext as if it were the plaintext would hand a guest
+    // `version || nonce || ct || tag` as key material. Anything structurally
+    // unlike a blob we produced is reported as `Legacy` for the caller to decode
+    // with the pre-wrap path.
+    pub fn unwrap_or_legacy(&self, column: &str) -> Result<MaybeWrapped> {
+        let decoded = match base64::decode(column) {
+            Ok(d) => d,
+            Err(_) => return Ok(MaybeWrapped::Legacy),
+        };
+        if decoded.len() < 1 + CONNECTION_IV_LENGTH + AES_GCM_TAG_LENGTH {
+            return Ok(MaybeWrapped::Legacy);
+        }
+        if !self.keys.contains_key(&decoded[0]) {
+            return Ok(MaybeWrapped::Legacy);
+        }
+        // Structurally a blob we produced: a decrypt failure here is a wrong or
+        // rotated-away key, so propagate it rather than leaking the ciphertext.
+        Ok(MaybeWrapped::Plaintext(self.unwrap(column)?))
+    }
+}
+
+// Resolve a master-key spec into 32 bytes of key material. A bare value is
+// base64-decoded; a URL is fetched from its backing store. Only `file://` is
+// wired up here; other KMS schemes slot in alongside it.
+fn resolve_master_key(spec: &str) -> Result<Vec<u8>> {
+    let material = if let Some(rest) = spec.strip_prefix("file://") {
+        base64::decode(std::fs::read_to_string(rest)?.trim())?
+    } else if spec.contains("://") {
+        bail!("unsupported KBS_MASTER_KEY scheme: {}", spec);
+    } else {
+        base64::decode(spec)?
+    };
+    if material.len() != MASTER_KEY_LENGTH {
+        bail!(
+            "master key must be {} bytes, got {}",
+            MASTER_KEY_LENGTH,
+            material.len()
+        );
+    }
+    Ok(material)
+}
+
+// Build the associated data that ties a packaged secret to the guest it was
+// produced for. These are exactly the fields that were attested, so a secret
+// packaged for one launch cannot be replayed against another: decryption on
+// the guest only succeeds if the same attested context is reconstructed.
+pub fn connection_aad(connection: &db::Connection) -> Vec<u8> {
+    // Length-prefix the variable-length fields so two distinct connections can
+    // never serialize to the same byte string by shifting bytes across a field
+    // boundary.
+    let mut aad = Vec::new();
+    aad.extend_from_slice(&(connection.fw_digest.len() as u32).to_le_bytes());
+    aad.extend_from_slice(connection.fw_digest.as_bytes());
+    aad.extend_from_slice(&connection.policy.to_le_bytes());
+    aad.extend_from_slice(&connection.fw_api_major.to_le_bytes());
+    aad.extend_from_slice(&connection.fw_api_minor.to_le_bytes());
+    aad.extend_from_slice(&connection.fw_build_id.to_le_bytes());
+    aad.extend_from_slice(&(connection.launch_description.len() as u32).to_le_bytes());
+    aad.extend_from_slice(connection.launch_description.as_bytes());
+    aad
+}
 
-pub fn encrypt_secret_payload(payload: &[u8], key: String) -> Result<(String, String)> {
+// Application-level AES-256-GCM envelope: encrypt `payload` under `key` with
+// `aad` bound into the tag, returning (ciphertext_b64, iv_b64). The SEV raw
+// response path does not use this — there the secret is sealed by the SEV
+// transport key (see `sev_tools::package_secret`) — so this is the primitive
+// for clients that fetch a secret outside the launch-secret channel and hold
+// `key` out of band. Its AAD binding is covered by `test_aad_binding`.
+pub fn encrypt_secret_payload(payload: &[u8], key: String, aad: &[u8]) -> Result<(String, String)> {
     let key_bytes = base64::decode(key)?;
     let k = Key::from_slice(&key_bytes);
     let cipher = Aes256Gcm::new(k);
@@ -19,7 +229,7 @@ pub fn encrypt_secret_payload(payload: &[u8], key: String) -> Result<(String, St
     let nonce = Nonce::from_slice(&iv);
 
     let encrypted_payload = cipher
-        .encrypt(nonce, payload)
+        .encrypt(nonce, Payload { msg: payload, aad })
         .map_err(|e| anyhow!("Encryption Error: {}", e))?;
 
     let encrypted_payload_b64 = base64::encode(encrypted_payload);
@@ -28,6 +238,139 @@ pub fn encrypt_secret_payload(payload: &[u8], key: String) -> Result<(String, St
     Ok((encrypted_payload_b64, iv_b64))
 }
 
+// Output format selected by the client in SecretRequest. `Raw` is the
+// historical behaviour (base64 AES-256-GCM blob with a side-channel IV);
+// `Cose` wraps the same ciphertext in a COSE_Encrypt0 structure so guests can
+// decrypt with a standard COSE library.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecretFormat {
+    Raw,
+    Cose,
+}
+
+impl SecretFormat {
+    pub fn parse(format: &str) -> Result<SecretFormat> {
+        match format {
+            // An unset field deserializes to the empty string; keep the
+            // historical raw behaviour as the default.
+            "" | "raw" => Ok(SecretFormat::Raw),
+            "cose" => Ok(SecretFormat::Cose),
+            other => Err(anyhow!("Unknown secret format: {}", other)),
+        }
+    }
+}
+
+// COSE algorithm identifier for AES-256-GCM (RFC 8152, table 5).
+const COSE_ALG_A256GCM: i64 = 3;
+// COSE header label for the algorithm (protected header).
+const COSE_LABEL_ALG: u64 = 1;
+// COSE header label for the IV (unprotected header).
+const COSE_LABEL_IV: u64 = 5;
+
+// Append a CBOR major-type header for `value` with major type `major` (0..=7).
+fn cbor_header(out: &mut Vec<u8>, major: u8, value: u64) {
+    let mt = major << 5;
+    if value < 24 {
+        out.push(mt | value as u8);
+    } else if value <= u8::MAX as u64 {
+        out.push(mt | 24);
+        out.push(value as u8);
+    } else if value <= u16::MAX as u64 {
+        out.push(mt | 25);
+        out.extend_from_slice(&(value as u16).to_be_bytes());
+    } else if value <= u32::MAX as u64 {
+        out.push(mt | 26);
+        out.extend_from_slice(&(value as u32).to_be_bytes());
+    } else {
+        out.push(mt | 27);
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+fn cbor_uint(out: &mut Vec<u8>, value: u64) {
+    cbor_header(out, 0, value);
+}
+
+fn cbor_int(out: &mut Vec<u8>, value: i64) {
+    if value < 0 {
+        cbor_header(out, 1, (-1 - value) as u64);
+    } else {
+        cbor_header(out, 0, value as u64);
+    }
+}
+
+fn cbor_bstr(out: &mut Vec<u8>, bytes: &[u8]) {
+    cbor_header(out, 2, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn cbor_tstr(out: &mut Vec<u8>, s: &str) {
+    cbor_header(out, 3, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+// Serialized (bstr-wrapped) protected header: a CBOR map {1: A256GCM}.
+fn cose_protected_header() -> Vec<u8> {
+    let mut map = Vec::new();
+    cbor_header(&mut map, 5, 1); // map of one pair
+    cbor_uint(&mut map, COSE_LABEL_ALG);
+    cbor_int(&mut map, COSE_ALG_A256GCM);
+    map
+}
+
+// The canonical Enc_structure that GCM must authenticate as additional data:
+// ["Encrypt0", protected_header_bstr, external_aad_bstr].
+fn cose_enc_structure(protected: &[u8], external_aad: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    cbor_header(&mut out, 4, 3); // array of three elements
+    cbor_tstr(&mut out, "Encrypt0");
+    cbor_bstr(&mut out, protected);
+    cbor_bstr(&mut out, external_aad);
+    out
+}
+
+// Encrypt `payload` and return a base64-encoded COSE_Encrypt0 structure:
+// [protected_header_bstr, {5: iv}, ciphertext || tag]. `external_aad` is bound
+// into the GCM tag via the canonical Enc_structure, so decryption only
+// succeeds when the same attested context is reconstructed.
+pub fn encrypt_secret_payload_cose(
+    payload: &[u8],
+    key: String,
+    external_aad: &[u8],
+) -> Result<String> {
+    let key_bytes = base64::decode(key)?;
+    let k = Key::from_slice(&key_bytes);
+    let cipher = Aes256Gcm::new(k);
+
+    let iv = rand::thread_rng().gen::<[u8; CONNECTION_IV_LENGTH]>();
+    let nonce = Nonce::from_slice(&iv);
+
+    let protected = cose_protected_header();
+    let aad = cose_enc_structure(&protected, external_aad);
+
+    // aes_gcm appends the 16-byte tag to the ciphertext, matching COSE.
+    let ciphertext = cipher
+        .encrypt(
+            nonce,
+            Payload {
+                msg: payload,
+                aad: &aad,
+            },
+        )
+        .map_err(|e| anyhow!("Encryption Error: {}", e))?;
+
+    let mut cose = Vec::new();
+    cbor_header(&mut cose, 4, 3); // COSE_Encrypt0 array
+    cbor_bstr(&mut cose, &protected);
+    // unprotected header map {5: iv}
+    cbor_header(&mut cose, 5, 1);
+    cbor_uint(&mut cose, COSE_LABEL_IV);
+    cbor_bstr(&mut cose, &iv);
+    cbor_bstr(&mut cose, &ciphertext);
+
+    Ok(base64::encode(cose))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -37,6 +380,7 @@ mod tests {
     #[test]
     pub fn test_payload_encryption() -> Result<()> {
         let payload = b"Test Payload";
+        let aad = connection_aad(&db::Connection::default());
 
         let key_bytes: Vec<u8> = rand::thread_rng()
             .gen::<[u8; CONNECTION_KEY_LENGTH]>()
@@ -46,18 +390,176 @@ mod tests {
 
         let cipher = Aes256Gcm::new(key);
 
-        let (encrypted_payload, iv) = encrypt_secret_payload(payload, key_b64)?;
+        let (encrypted_payload, iv) = encrypt_secret_payload(payload, key_b64, &aad)?;
         let payload_bytes = base64::decode(encrypted_payload)?;
         let iv_bytes = base64::decode(iv)?;
 
         let nonce = Nonce::from_slice(&iv_bytes);
 
         let decrypted_payload = cipher
-            .decrypt(nonce, payload_bytes.as_ref())
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: payload_bytes.as_ref(),
+                    aad: &aad,
+                },
+            )
             .expect("Failed to decrypt.");
 
         assert_eq!(&decrypted_payload, payload);
 
         Ok(())
     }
+
+    #[test]
+    pub fn test_aad_binding() -> Result<()> {
+        // A secret packaged for one connection must not decrypt under the
+        // associated data of a different connection.
+        let payload = b"Test Payload";
+
+        let connection = db::Connection::default();
+        let other = db::Connection {
+            launch_description: "different".to_string(),
+            ..db::Connection::default()
+        };
+
+        let key_bytes: Vec<u8> = rand::thread_rng()
+            .gen::<[u8; CONNECTION_KEY_LENGTH]>()
+            .to_vec();
+        let key_b64 = base64::encode(&key_bytes);
+        let key = Key::from_slice(&key_bytes);
+
+        let cipher = Aes256Gcm::new(key);
+
+        let (encrypted_payload, iv) =
+            encrypt_secret_payload(payload, key_b64, &connection_aad(&connection))?;
+        let payload_bytes = base64::decode(encrypted_payload)?;
+        let iv_bytes = base64::decode(iv)?;
+        let nonce = Nonce::from_slice(&iv_bytes);
+
+        let result = cipher.decrypt(
+            nonce,
+            Payload {
+                msg: payload_bytes.as_ref(),
+                aad: &connection_aad(&other),
+            },
+        );
+
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    // Read a definite-length byte/text string at `pos`, returning its contents
+    // and the position just past it. Only the minimal subset of CBOR the
+    // COSE_Encrypt0 encoder emits is handled.
+    fn read_str(buf: &[u8], pos: usize) -> (&[u8], usize) {
+        let ib = buf[pos];
+        let short = (ib & 0x1f) as usize;
+        let (len, mut p) = match short {
+            0..=23 => (short, pos + 1),
+            24 => (buf[pos + 1] as usize, pos + 2),
+            25 => (u16::from_be_bytes([buf[pos + 1], buf[pos + 2]]) as usize, pos + 3),
+            _ => panic!("unexpected cbor length encoding"),
+        };
+        let s = &buf[p..p + len];
+        p += len;
+        (s, p)
+    }
+
+    fn keyring(version: u8, key: Vec<u8>) -> MasterKeyring {
+        let mut keys = HashMap::new();
+        keys.insert(version, key);
+        MasterKeyring {
+            keys,
+            current_version: version,
+        }
+    }
+
+    #[test]
+    pub fn test_at_rest_wrap_roundtrip() -> Result<()> {
+        let key = rand::thread_rng()
+            .gen::<[u8; MASTER_KEY_LENGTH]>()
+            .to_vec();
+        let ring = keyring(1, key);
+
+        let plaintext = b"wrapped symkey material";
+        let blob = ring.wrap(plaintext)?;
+        // The blob carries the version byte so rotation stays readable.
+        assert_eq!(base64::decode(&blob)?[0], 1);
+        assert_eq!(ring.unwrap(&blob)?, plaintext);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_at_rest_rotation_reads_old_version() -> Result<()> {
+        let old = rand::thread_rng()
+            .gen::<[u8; MASTER_KEY_LENGTH]>()
+            .to_vec();
+        let new = rand::thread_rng()
+            .gen::<[u8; MASTER_KEY_LENGTH]>()
+            .to_vec();
+
+        // A row wrapped under version 1 must still unwrap after the current
+        // version has rotated to 2.
+        let v1 = keyring(1, old.clone());
+        let blob = v1.wrap(b"legacy row")?;
+
+        let mut keys = HashMap::new();
+        keys.insert(1, old);
+        keys.insert(2, new);
+        let rotated = MasterKeyring {
+            keys,
+            current_version: 2,
+        };
+        assert_eq!(rotated.unwrap(&blob)?, b"legacy row");
+        assert_eq!(base64::decode(rotated.wrap(b"fresh")?)?[0], 2);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_cose_encrypt0() -> Result<()> {
+        let payload = b"Test Payload";
+        let external_aad = connection_aad(&db::Connection::default());
+
+        let key_bytes: Vec<u8> = rand::thread_rng()
+            .gen::<[u8; CONNECTION_KEY_LENGTH]>()
+            .to_vec();
+        let key_b64 = base64::encode(&key_bytes);
+        let key = Key::from_slice(&key_bytes);
+        let cipher = Aes256Gcm::new(key);
+
+        let cose_b64 = encrypt_secret_payload_cose(payload, key_b64, &external_aad)?;
+        let cose = base64::decode(cose_b64)?;
+
+        // COSE_Encrypt0 array of three elements.
+        assert_eq!(cose[0], 0x83);
+        let mut pos = 1;
+
+        // Protected header bstr: {1: 3} -> a1 01 03.
+        let (protected, next) = read_str(&cose, pos);
+        assert_eq!(protected, [0xa1, 0x01, 0x03]);
+        pos = next;
+
+        // Unprotected header map {5: <iv>}.
+        assert_eq!(cose[pos], 0xa1);
+        assert_eq!(cose[pos + 1], COSE_LABEL_IV as u8);
+        let (iv, next) = read_str(&cose, pos + 2);
+        assert_eq!(iv.len(), CONNECTION_IV_LENGTH);
+        pos = next;
+
+        // Ciphertext (with appended tag).
+        let (ciphertext, _) = read_str(&cose, pos);
+
+        // Reconstruct the Enc_structure and decrypt.
+        let aad = cose_enc_structure(protected, &external_aad);
+        let nonce = Nonce::from_slice(iv);
+        let decrypted = cipher
+            .decrypt(nonce, Payload { msg: ciphertext, aad: &aad })
+            .expect("Failed to decrypt COSE_Encrypt0");
+
+        assert_eq!(&decrypted, payload);
+
+        Ok(())
+    }
 }
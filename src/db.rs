@@ -3,13 +3,18 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use crate::crypto;
 use crate::policy;
 use crate::request;
 
 use anyhow::*;
+use log::{debug, warn};
 use rand::Rng;
+use std::collections::HashMap;
 use std::env;
+use std::hash::{Hash, Hasher};
 use std::result::Result::Ok;
+use std::time::Duration;
 use uuid::Uuid;
 
 use regex::{Captures, Regex};
@@ -20,6 +25,156 @@ use sqlx::Row;
 
 const CONNECTION_KEY_LENGTH: usize = 32;
 
+// Connection symkeys are refreshed roughly once a week. Each connection's
+// deadline is `create_date + REFRESH_INTERVAL + connection_jitter(id)`, a jitter
+// fixed per connection so that a cohort created together does not all rotate in
+// the same sweep (thundering herd) and no row drifts toward 3*REFRESH_INTERVAL.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+// Connections untouched for this long are deleted outright rather than rotated.
+const DEFAULT_CONNECTION_HARD_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+// Lower bound on the interval between sweeper wakeups; keeps a short configured interval
+// from busy-looping the database.
+const MIN_SLEEP_TIME: Duration = Duration::from_secs(300);
+
+// Env overrides for the sweeper, all expressed in seconds.
+const CONN_REFRESH_INTERVAL_ENV: &str = "KBS_CONN_REFRESH_INTERVAL";
+const CONN_HARD_TTL_ENV: &str = "KBS_CONN_HARD_TTL";
+const CONN_SWEEP_INTERVAL_ENV: &str = "KBS_CONN_SWEEP_INTERVAL";
+
+// Rewrite the `?` placeholders the queries are written with into the `$1, $2,
+// ...` form Postgres requires; other backends take the SQL unchanged. Shared by
+// `KbsDb` and the transaction guard so both speak the portability dialect.
+fn replace_binds_for(kind: AnyKind, sql: &str) -> String {
+    if kind != AnyKind::Postgres {
+        return sql.to_string();
+    }
+
+    let question_mark_re = Regex::new(r"\?").unwrap();
+    let mut counter = 0;
+    let result = question_mark_re.replace_all(sql, |_: &Captures| {
+        counter += 1;
+        format!("${}", counter)
+    });
+    result.to_string()
+}
+
+// Per-connection jitter in `[0, 2*d)`, derived deterministically from the
+// connection id. Adding it to a fixed refresh interval spreads rotation work
+// across the window instead of bunching it; deriving it from the id (rather
+// than a fresh random draw) keeps each connection's deadline stable across
+// sweeps, so an unlucky row cannot be deferred repeatedly toward `3*d`.
+fn connection_jitter(id: &str, d: Duration) -> Duration {
+    let max = d.as_secs().saturating_mul(2);
+    if max == 0 {
+        return Duration::from_secs(0);
+    }
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    id.hash(&mut hasher);
+    Duration::from_secs(hasher.finish() % max)
+}
+
+// Tuning for the background connection sweeper, populated from the environment.
+#[derive(Debug, Clone, Copy)]
+pub struct SweeperConfig {
+    pub refresh_interval: Duration,
+    pub hard_ttl: Duration,
+    pub sweep_interval: Duration,
+}
+
+impl Default for SweeperConfig {
+    fn default() -> Self {
+        SweeperConfig {
+            refresh_interval: REFRESH_INTERVAL,
+            hard_ttl: DEFAULT_CONNECTION_HARD_TTL,
+            sweep_interval: MIN_SLEEP_TIME,
+        }
+    }
+}
+
+impl SweeperConfig {
+    fn from_env() -> Self {
+        fn secs(var: &str, default: Duration) -> Duration {
+            match env::var(var).ok().and_then(|v| v.parse::<u64>().ok()) {
+                Some(s) => Duration::from_secs(s),
+                None => default,
+            }
+        }
+        let mut sweep = secs(CONN_SWEEP_INTERVAL_ENV, MIN_SLEEP_TIME);
+        if sweep < MIN_SLEEP_TIME {
+            sweep = MIN_SLEEP_TIME;
+        }
+        SweeperConfig {
+            refresh_interval: secs(CONN_REFRESH_INTERVAL_ENV, REFRESH_INTERVAL),
+            hard_ttl: secs(CONN_HARD_TTL_ENV, DEFAULT_CONNECTION_HARD_TTL),
+            sweep_interval: sweep,
+        }
+    }
+}
+
+// Bump whenever the dump layout changes so an older restore refuses a document
+// it cannot faithfully reload.
+const BACKUP_FORMAT_VERSION: u32 = 1;
+
+// A portable snapshot of the whole datastore. Serializes to JSON and reloads on
+// a fresh (or existing) database via `KbsDb::export`/`KbsDb::import`. Key
+// material stays wrapped exactly as stored, so a backup carries ciphertext only
+// and is migratable between backends that share a master key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KbsBackup {
+    pub version: u32,
+    pub policies: Vec<PolicyRow>,
+    pub connections: Vec<ConnectionRow>,
+    pub keysets: Vec<KeysetRow>,
+    pub secrets: Vec<SecretRow>,
+    pub report_keypairs: Vec<ReportKeypairRow>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyRow {
+    pub id: i64,
+    pub allowed_digests: String,
+    pub allowed_policies: String,
+    pub min_fw_api_major: i64,
+    pub min_fw_api_minor: i64,
+    pub allowed_build_ids: String,
+    pub create_date: String,
+    pub valid: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionRow {
+    pub id: String,
+    pub policy: i64,
+    pub fw_api_major: i64,
+    pub fw_api_minor: i64,
+    pub fw_build_id: i64,
+    pub launch_description: String,
+    pub fw_digest: String,
+    pub symkey: String,
+    pub create_date: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeysetRow {
+    pub keysetid: String,
+    pub kskeys: String,
+    pub polid: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecretRow {
+    pub secret_id: String,
+    pub secret: String,
+    pub polid: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReportKeypairRow {
+    pub key_id: String,
+    pub keypair: String,
+    pub polid: Option<i64>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Connection {
     pub policy: u32,
@@ -43,8 +198,57 @@ impl Default for Connection {
     }
 }
 
+// Connection-pool tuning, populated from the environment. The defaults
+// preserve the historical behaviour (a large pool, no explicit timeouts) while
+// letting deployments dial the pool down and, for SQLite, turn on the pragmas
+// that make concurrent access behave.
+#[derive(Debug, Clone, Copy)]
+pub struct KbsDbConfig {
+    pub max_connections: u32,
+    pub acquire_timeout: Duration,
+    // Milliseconds SQLite waits on a locked database before giving up, wired
+    // into `PRAGMA busy_timeout`.
+    pub busy_timeout_ms: u64,
+}
+
+impl Default for KbsDbConfig {
+    fn default() -> Self {
+        KbsDbConfig {
+            max_connections: 1000,
+            acquire_timeout: Duration::from_secs(30),
+            busy_timeout_ms: 5000,
+        }
+    }
+}
+
+impl KbsDbConfig {
+    fn from_env() -> Self {
+        let d = KbsDbConfig::default();
+        KbsDbConfig {
+            max_connections: env::var("KBS_DB_MAX_CONNECTIONS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(d.max_connections),
+            acquire_timeout: env::var("KBS_DB_ACQUIRE_TIMEOUT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(d.acquire_timeout),
+            busy_timeout_ms: env::var("KBS_DB_BUSY_TIMEOUT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(d.busy_timeout_ms),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct KbsDb {
     dbpool: AnyPool,
+    // Wraps key material before it is bound into a query and unwraps it on the
+    // way out, so the `secret`, `symkey`, and `keypair` columns are ciphertext
+    // at rest. Callers are unaffected.
+    keyring: crypto::MasterKeyring,
 }
 
 impl KbsDb {
@@ -64,32 +268,169 @@ impl KbsDb {
             )
         };
 
-        let dbpool = AnyPoolOptions::new()
-            .max_connections(1000)
-            .connect(&db_url)
-            .await
-            .map_err(|e| {
-                anyhow!(
-                    "db::get_db_pool:: Encountered error trying to create database pool: {}",
-                    e
-                )
-            })?;
-        Ok(Self { dbpool })
+        let config = KbsDbConfig::from_env();
+        let mut options = AnyPoolOptions::new()
+            .max_connections(config.max_connections)
+            .connect_timeout(config.acquire_timeout);
+
+        // SQLite defaults leave foreign keys off and fail fast on a locked
+        // database; turn on referential integrity, a busy timeout (so the
+        // concurrent test_stress load stops hitting "database is locked"), and
+        // WAL journalling for the life of every pooled connection.
+        if db_type == "sqlite" {
+            let busy_timeout_ms = config.busy_timeout_ms;
+            options = options.after_connect(move |conn| {
+                Box::pin(async move {
+                    sqlx::query("PRAGMA foreign_keys = ON")
+                        .execute(&mut *conn)
+                        .await?;
+                    sqlx::query(&format!("PRAGMA busy_timeout = {}", busy_timeout_ms))
+                        .execute(&mut *conn)
+                        .await?;
+                    sqlx::query("PRAGMA journal_mode = WAL")
+                        .execute(&mut *conn)
+                        .await?;
+                    Ok(())
+                })
+            });
+        }
+
+        let dbpool = options.connect(&db_url).await.map_err(|e| {
+            anyhow!(
+                "db::get_db_pool:: Encountered error trying to create database pool: {}",
+                e
+            )
+        })?;
+        let keyring = crypto::MasterKeyring::from_env()?;
+        let db = Self { dbpool, keyring };
+        db.spawn_connection_sweeper(SweeperConfig::from_env());
+        Ok(db)
     }
 
-    fn replace_binds(&self, sql: &str) -> String {
-        if self.dbpool.any_kind() != AnyKind::Postgres {
-            return sql.to_string();
+    // Spawn a background task that periodically rotates the symkeys of stale
+    // connections and deletes connections past the hard TTL. It holds its own
+    // clone of the pool/keyring so the returned `KbsDb` stays usable.
+    fn spawn_connection_sweeper(&self, config: SweeperConfig) {
+        let db = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(config.sweep_interval);
+            loop {
+                ticker.tick().await;
+                match db.sweep_connections(&config).await {
+                    Ok((deleted, rotated)) if deleted > 0 || rotated > 0 => {
+                        debug!(
+                            "Connection sweep: rotated {} symkey(s), deleted {} stale connection(s)",
+                            rotated, deleted
+                        );
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Connection sweep failed: {}", e),
+                }
+            }
+        });
+    }
+
+    // Delete connections past the hard TTL, then rotate the symkeys of those
+    // past their jittered refresh deadline. Returns (deleted, rotated).
+    async fn sweep_connections(&self, config: &SweeperConfig) -> Result<(u64, u64)> {
+        let deleted = self
+            .delete_connections_older_than(config.hard_ttl.as_secs())
+            .await?;
+        let rotated = self.rotate_stale_connections(config.refresh_interval).await?;
+        Ok((deleted, rotated))
+    }
+
+    // SQL predicate selecting rows whose `column` timestamp is at least `secs`
+    // seconds in the past, spelled for the active backend. `secs` is an
+    // internally computed integer, so inlining it raises no injection concern.
+    fn older_than_clause(&self, column: &str, secs: u64) -> String {
+        match self.dbpool.any_kind() {
+            AnyKind::Sqlite => format!("{} <= DATETIME('now', '-{} seconds')", column, secs),
+            AnyKind::MySql => format!("{} <= (NOW() - INTERVAL {} SECOND)", column, secs),
+            _ => format!("{} <= (NOW() - INTERVAL '{} seconds')", column, secs),
         }
+    }
 
-        // Replace question marks by $1, $2, ...
-        let question_mark_re = Regex::new(r"\?").unwrap();
-        let mut counter = 0;
-        let result = question_mark_re.replace_all(sql, |_: &Captures| {
-            counter += 1;
-            format!("${}", counter)
-        });
-        result.to_string()
+    // SQL expression giving the age in whole seconds of timestamp `column`,
+    // spelled for the active backend. Used to apply a per-row rotation deadline.
+    fn age_seconds_expr(&self, column: &str) -> String {
+        match self.dbpool.any_kind() {
+            AnyKind::Sqlite => format!(
+                "CAST(strftime('%s', 'now') - strftime('%s', {}) AS INTEGER)",
+                column
+            ),
+            AnyKind::MySql => format!("TIMESTAMPDIFF(SECOND, {}, NOW())", column),
+            _ => format!("CAST(EXTRACT(EPOCH FROM (NOW() - {})) AS BIGINT)", column),
+        }
+    }
+
+    // Backend-specific expression for the current time, used when stamping a
+    // freshly rotated connection's `create_date`.
+    fn now_expr(&self) -> &'static str {
+        match self.dbpool.any_kind() {
+            AnyKind::Sqlite => "DATE('now')",
+            _ => "NOW()",
+        }
+    }
+
+    async fn delete_connections_older_than(&self, secs: u64) -> Result<u64> {
+        let query_str = format!(
+            "DELETE FROM conn_bundle WHERE {}",
+            self.older_than_clause("create_date", secs)
+        );
+        let result = sqlx::query(&self.replace_binds(&query_str))
+            .execute(&self.dbpool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn rotate_stale_connections(&self, refresh_interval: Duration) -> Result<u64> {
+        // Select every connection past the earliest possible deadline (the
+        // un-jittered interval); each row then gets its own jittered deadline
+        // below, so a cohort created together does not all rotate in one sweep.
+        let select_str = format!(
+            "SELECT id, {} FROM conn_bundle WHERE {}",
+            self.age_seconds_expr("create_date"),
+            self.older_than_clause("create_date", refresh_interval.as_secs())
+        );
+        let rows = sqlx::query(&self.replace_binds(&select_str))
+            .fetch_all(&self.dbpool)
+            .await?;
+
+        let update_str = format!(
+            "UPDATE conn_bundle SET symkey = ?, create_date = {} WHERE id = ?",
+            self.now_expr()
+        );
+        let update_str = self.replace_binds(&update_str);
+
+        let mut rotated = 0;
+        for row in rows {
+            let id = row.try_get::<String, _>(0)?;
+            // Stable per-connection deadline: create_date + interval + jitter,
+            // where the jitter is fixed for this id across sweeps.
+            let age_secs = row.try_get::<i64, _>(1)?.max(0) as u64;
+            let deadline_secs =
+                refresh_interval.as_secs() + connection_jitter(&id, refresh_interval).as_secs();
+            if age_secs < deadline_secs {
+                continue;
+            }
+
+            let key_bytes = rand::thread_rng().gen::<[u8; CONNECTION_KEY_LENGTH]>();
+            let key_b64 = base64::encode(key_bytes);
+            let symkey_wrapped = self.keyring.wrap(key_b64.as_bytes())?;
+
+            sqlx::query(&update_str)
+                .bind(symkey_wrapped)
+                .bind(id)
+                .execute(&self.dbpool)
+                .await?;
+            rotated += 1;
+        }
+        Ok(rotated)
+    }
+
+    fn replace_binds(&self, sql: &str) -> String {
+        replace_binds_for(self.dbpool.any_kind(), sql)
     }
 
     pub async fn insert_connection(&self, connection: Connection) -> Result<(Uuid, String)> {
@@ -98,6 +439,9 @@ impl KbsDb {
 
         let key_bytes = rand::thread_rng().gen::<[u8; CONNECTION_KEY_LENGTH]>();
         let key_b64 = base64::encode(key_bytes);
+        // The symkey is wrapped at rest; the caller still receives the plaintext
+        // base64 key so the attestation flow is unchanged.
+        let symkey_wrapped = self.keyring.wrap(key_b64.as_bytes())?;
 
         let db_type = env::var("KBS_DB_TYPE").expect("KBS_DB_TYPE not set");
         let query_str = if db_type == "sqlite" {
@@ -116,7 +460,7 @@ impl KbsDb {
             .bind(connection.fw_build_id as i64)
             .bind(&connection.launch_description)
             .bind(&connection.fw_digest)
-            .bind(key_b64.clone())
+            .bind(symkey_wrapped)
             .execute(&self.dbpool)
             .await?;
         Ok((nwuuid, key_b64))
@@ -142,7 +486,16 @@ impl KbsDb {
             fw_digest: con_row.try_get::<String, _>(5)?,
         };
 
-        Ok((connection, con_row.try_get::<String, _>(6)?))
+        // Tolerate rows written before at-rest wrapping: a legacy symkey is the
+        // base64 key string stored verbatim, which is not a versioned AEAD blob
+        // and so fails to unwrap. It is re-wrapped the next time the connection
+        // is rotated or reinserted.
+        let symkey_col = con_row.try_get::<String, _>(6)?;
+        let symkey = match self.keyring.unwrap_or_legacy(&symkey_col)? {
+            crypto::MaybeWrapped::Plaintext(bytes) => String::from_utf8(bytes)?,
+            crypto::MaybeWrapped::Legacy => symkey_col,
+        };
+        Ok((connection, symkey))
     }
 
     pub async fn delete_connection(&self, uuid: Uuid) -> Result<Uuid> {
@@ -216,6 +569,10 @@ impl KbsDb {
             min_fw_api_major: policy_row.try_get::<i32, _>(2)? as u32,
             min_fw_api_minor: policy_row.try_get::<i32, _>(3)? as u32,
             allowed_build_ids: serde_json::from_str(&policy_row.try_get::<String, _>(4)?)?,
+            // DICE identity checks are not persisted in the policy table.
+            allowed_dice_roots: vec![],
+            allowed_code_hashes: vec![],
+            min_security_version: None,
         })
     }
 
@@ -230,7 +587,11 @@ impl KbsDb {
         Ok(())
     }
 
-    pub async fn get_secret_policy(&self, sec: &str) -> Result<policy::Policy> {
+    // Look up the policy attached to a secret. `Ok(None)` means the secret
+    // exists but carries no policy (NULL `polid`); any error — a missing row or
+    // a backend failure — is propagated so callers can fail closed rather than
+    // mistake a lookup failure for "no policy".
+    pub async fn get_secret_policy(&self, sec: &str) -> Result<Option<policy::Policy>> {
         let query_str = "SELECT polid FROM secrets WHERE secret_id = ?";
         let new_query_str = self.replace_binds(query_str);
 
@@ -238,9 +599,10 @@ impl KbsDb {
             .bind(sec)
             .fetch_one(&self.dbpool)
             .await?;
-        let pol = pol_row.try_get::<i64, _>(0)? as u64;
-        let secret_policy = self.get_policy(pol).await?;
-        Ok(secret_policy)
+        match pol_row.try_get::<Option<i64>, _>(0)? {
+            Some(pol) => Ok(Some(self.get_policy(pol as u64).await?)),
+            None => Ok(None),
+        }
     }
 
     pub async fn insert_keyset(
@@ -320,7 +682,14 @@ impl KbsDb {
             .bind(secret_id)
             .fetch_one(&self.dbpool)
             .await?;
-        let secret = secret_row.try_get::<String, _>(0)?;
+        // Legacy rows (written before at-rest wrapping) hold the secret string
+        // verbatim; fall back to it when the column is not a wrapped blob. Such
+        // rows are re-wrapped the next time the secret is written.
+        let secret_col = secret_row.try_get::<String, _>(0)?;
+        let secret = match self.keyring.unwrap_or_legacy(&secret_col)? {
+            crypto::MaybeWrapped::Plaintext(bytes) => String::from_utf8(bytes)?,
+            crypto::MaybeWrapped::Legacy => secret_col,
+        };
         Ok(request::Key {
             id: secret_id.to_string(),
             payload: secret,
@@ -333,11 +702,12 @@ impl KbsDb {
         secret: &str,
         policy_id: Option<u64>,
     ) -> Result<()> {
+        let secret_wrapped = self.keyring.wrap(secret.as_bytes())?;
         let query_str = "INSERT INTO secrets (secret_id, secret, polid ) VALUES(?, ?, ?)";
         let new_query_str = self.replace_binds(query_str);
         sqlx::query(&new_query_str)
             .bind(secret_id)
-            .bind(secret)
+            .bind(secret_wrapped)
             .bind(policy_id.map(|p| p as i64))
             .execute(&self.dbpool)
             .await?;
@@ -361,12 +731,12 @@ impl KbsDb {
         keypair: &[u8],
         policy_id: Option<u64>,
     ) -> Result<()> {
-        let keypair_b64 = base64::encode(&keypair);
+        let keypair_wrapped = self.keyring.wrap(keypair)?;
         let query_str = "INSERT INTO report_keypair (key_id, keypair, polid ) VALUES(?, ?, ?)";
         let new_query_str = self.replace_binds(query_str);
         sqlx::query(&new_query_str)
             .bind(id)
-            .bind(&keypair_b64)
+            .bind(&keypair_wrapped)
             .bind(policy_id.map(|p| p as i64))
             .execute(&self.dbpool)
             .await?;
@@ -381,8 +751,14 @@ impl KbsDb {
             .bind(id)
             .fetch_one(&self.dbpool)
             .await?;
-        let kp = key_row.try_get::<String, _>(0)?;
-        let kp_bytes = base64::decode(&kp)?;
+        // Legacy rows stored the pkcs8 key base64-encoded rather than wrapped;
+        // fall back to decoding them when the column is not a wrapped blob. Such
+        // rows are re-wrapped the next time the keypair is written.
+        let kp_col = key_row.try_get::<String, _>(0)?;
+        let kp_bytes = match self.keyring.unwrap_or_legacy(&kp_col)? {
+            crypto::MaybeWrapped::Plaintext(bytes) => bytes,
+            crypto::MaybeWrapped::Legacy => base64::decode(&kp_col)?,
+        };
         Ok(kp_bytes)
     }
 
@@ -412,6 +788,428 @@ impl KbsDb {
             None => Ok(None),
         }
     }
+
+    // Begin an atomic unit of work. The returned guard exposes the insert
+    // methods that compose into a single request (e.g. a policy plus the
+    // secret/keyset/report_keypair that reference it) and must be finished with
+    // `commit`; dropping it without committing rolls back, so a crash between
+    // statements can never leave a dangling `polid`.
+    pub async fn transaction(&self) -> Result<KbsTransaction<'_>> {
+        let kind = self.dbpool.any_kind();
+        let tx = self.dbpool.begin().await?;
+        Ok(KbsTransaction {
+            tx,
+            kind,
+            keyring: self.keyring.clone(),
+        })
+    }
+
+    // Serialize every row of the datastore into a versioned `KbsBackup`. Key
+    // material is copied out wrapped, so the dump never contains plaintext.
+    pub async fn export(&self) -> Result<KbsBackup> {
+        let policies = sqlx::query("SELECT id, allowed_digests, allowed_policies, min_fw_api_major, min_fw_api_minor, allowed_build_ids, create_date, valid FROM policy")
+            .fetch_all(&self.dbpool)
+            .await?
+            .into_iter()
+            .map(|r| {
+                Ok(PolicyRow {
+                    id: r.try_get(0)?,
+                    allowed_digests: r.try_get(1)?,
+                    allowed_policies: r.try_get(2)?,
+                    min_fw_api_major: r.try_get(3)?,
+                    min_fw_api_minor: r.try_get(4)?,
+                    allowed_build_ids: r.try_get(5)?,
+                    create_date: r.try_get(6)?,
+                    valid: r.try_get(7)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let connections = sqlx::query("SELECT id, policy, fw_api_major, fw_api_minor, fw_build_id, launch_description, fw_digest, symkey, create_date FROM conn_bundle")
+            .fetch_all(&self.dbpool)
+            .await?
+            .into_iter()
+            .map(|r| {
+                Ok(ConnectionRow {
+                    id: r.try_get(0)?,
+                    policy: r.try_get(1)?,
+                    fw_api_major: r.try_get(2)?,
+                    fw_api_minor: r.try_get(3)?,
+                    fw_build_id: r.try_get(4)?,
+                    launch_description: r.try_get(5)?,
+                    fw_digest: r.try_get(6)?,
+                    symkey: r.try_get(7)?,
+                    create_date: r.try_get(8)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let keysets = sqlx::query("SELECT keysetid, kskeys, polid FROM keysets")
+            .fetch_all(&self.dbpool)
+            .await?
+            .into_iter()
+            .map(|r| {
+                Ok(KeysetRow {
+                    keysetid: r.try_get(0)?,
+                    kskeys: r.try_get(1)?,
+                    polid: r.try_get(2)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let secrets = sqlx::query("SELECT secret_id, secret, polid FROM secrets")
+            .fetch_all(&self.dbpool)
+            .await?
+            .into_iter()
+            .map(|r| {
+                Ok(SecretRow {
+                    secret_id: r.try_get(0)?,
+                    secret: r.try_get(1)?,
+                    polid: r.try_get(2)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let report_keypairs = sqlx::query("SELECT key_id, keypair, polid FROM report_keypair")
+            .fetch_all(&self.dbpool)
+            .await?
+            .into_iter()
+            .map(|r| {
+                Ok(ReportKeypairRow {
+                    key_id: r.try_get(0)?,
+                    keypair: r.try_get(1)?,
+                    polid: r.try_get(2)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(KbsBackup {
+            version: BACKUP_FORMAT_VERSION,
+            policies,
+            connections,
+            keysets,
+            secrets,
+            report_keypairs,
+        })
+    }
+
+    // Reload a `KbsBackup` produced by `export`. The existing rows of the five
+    // tables are cleared and the backup reinserted inside one transaction, so a
+    // half-applied restore never corrupts the store and re-running the same
+    // backup is idempotent. Policies are inserted first; their fresh
+    // auto-increment ids are remapped into the `polid` of every referencing
+    // row, preserving the original linkages.
+    pub async fn import(&self, backup: &KbsBackup) -> Result<()> {
+        if backup.version != BACKUP_FORMAT_VERSION {
+            bail!(
+                "unsupported backup format version {} (expected {})",
+                backup.version,
+                BACKUP_FORMAT_VERSION
+            );
+        }
+
+        let mut tx = self.transaction().await?;
+        tx.clear_datastore().await?;
+
+        let mut polmap: HashMap<i64, i64> = HashMap::new();
+        for p in &backup.policies {
+            let new_id = tx.insert_policy_row(p).await?;
+            polmap.insert(p.id, new_id);
+        }
+
+        let remap = |polid: Option<i64>| -> Result<Option<i64>> {
+            match polid {
+                None => Ok(None),
+                Some(old) => polmap
+                    .get(&old)
+                    .copied()
+                    .map(Some)
+                    .ok_or_else(|| anyhow!("backup references unknown policy id {}", old)),
+            }
+        };
+
+        for c in &backup.connections {
+            tx.insert_connection_row(c).await?;
+        }
+        for s in &backup.secrets {
+            tx.insert_secret_row(&s.secret_id, &s.secret, remap(s.polid)?)
+                .await?;
+        }
+        for k in &backup.keysets {
+            tx.insert_keyset_row(&k.keysetid, &k.kskeys, remap(k.polid)?)
+                .await?;
+        }
+        for rk in &backup.report_keypairs {
+            tx.insert_report_keypair_row(&rk.key_id, &rk.keypair, remap(rk.polid)?)
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+// A guard over an open `sqlx` transaction exposing the subset of `KbsDb` writes
+// that callers batch into one atomic unit. Binds against the transaction rather
+// than the pool; finish with `commit` or `rollback`.
+pub struct KbsTransaction<'a> {
+    tx: sqlx::Transaction<'a, sqlx::Any>,
+    kind: AnyKind,
+    keyring: crypto::MasterKeyring,
+}
+
+impl<'a> KbsTransaction<'a> {
+    fn replace_binds(&self, sql: &str) -> String {
+        replace_binds_for(self.kind, sql)
+    }
+
+    fn now_expr(&self) -> &'static str {
+        match self.kind {
+            AnyKind::Sqlite => "DATE('now')",
+            _ => "NOW()",
+        }
+    }
+
+    pub async fn insert_policy(&mut self, policy: &policy::Policy) -> Result<u64> {
+        let allowed_digests_json = serde_json::to_string(&policy.allowed_digests)?;
+        let allowed_policies_json = serde_json::to_string(&policy.allowed_policies)?;
+        let allowed_build_ids_json = serde_json::to_string(&policy.allowed_build_ids)?;
+
+        let mut query_str = format!(
+            "INSERT INTO policy (allowed_digests, allowed_policies, min_fw_api_major, min_fw_api_minor, allowed_build_ids, create_date, valid) VALUES(?, ?, ?, ?, ?, {}, 1)",
+            self.now_expr()
+        );
+
+        if self.kind == AnyKind::MySql || self.kind == AnyKind::Sqlite {
+            let last_insert_row = sqlx::query(&query_str)
+                .bind(allowed_digests_json)
+                .bind(allowed_policies_json)
+                .bind(policy.min_fw_api_major as i64)
+                .bind(policy.min_fw_api_minor as i64)
+                .bind(allowed_build_ids_json)
+                .execute(&mut *self.tx)
+                .await?
+                .last_insert_id();
+            match last_insert_row {
+                Some(p) => Ok(p as u64),
+                None => Err(anyhow!(
+                    "db::insert_policy- error, last_insert_id() returned None"
+                )),
+            }
+        } else {
+            query_str.push_str("RETURNING id");
+            let new_query_str = self.replace_binds(&query_str);
+            let last_insert_row = sqlx::query(&new_query_str)
+                .bind(allowed_digests_json)
+                .bind(allowed_policies_json)
+                .bind(policy.min_fw_api_major as i64)
+                .bind(policy.min_fw_api_minor as i64)
+                .bind(allowed_build_ids_json)
+                .fetch_one(&mut *self.tx)
+                .await?;
+            Ok(last_insert_row.try_get::<i32, _>(0)? as u64)
+        }
+    }
+
+    pub async fn insert_secret(
+        &mut self,
+        secret_id: &str,
+        secret: &str,
+        policy_id: Option<u64>,
+    ) -> Result<()> {
+        let secret_wrapped = self.keyring.wrap(secret.as_bytes())?;
+        let query_str = "INSERT INTO secrets (secret_id, secret, polid ) VALUES(?, ?, ?)";
+        let new_query_str = self.replace_binds(query_str);
+        sqlx::query(&new_query_str)
+            .bind(secret_id)
+            .bind(secret_wrapped)
+            .bind(policy_id.map(|p| p as i64))
+            .execute(&mut *self.tx)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn insert_keyset(
+        &mut self,
+        ksetid: &str,
+        kskeys: &[String],
+        polid: Option<u32>,
+    ) -> Result<()> {
+        let kskeys_str = serde_json::to_string(kskeys)?;
+
+        match polid {
+            Some(p) => {
+                let query_str = "INSERT INTO keysets (keysetid, kskeys, polid) VALUES(?, ?, ?)";
+                let new_query_str = self.replace_binds(query_str);
+                sqlx::query(&new_query_str)
+                    .bind(ksetid)
+                    .bind(&kskeys_str)
+                    .bind(p as i64)
+                    .execute(&mut *self.tx)
+                    .await?;
+            }
+            None => {
+                let query_str = "INSERT INTO keysets (keysetid, kskeys) VALUES(?, ?)";
+                let new_query_str = self.replace_binds(query_str);
+                sqlx::query(&new_query_str)
+                    .bind(ksetid)
+                    .bind(&kskeys_str)
+                    .execute(&mut *self.tx)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn insert_report_keypair(
+        &mut self,
+        id: &str,
+        keypair: &[u8],
+        policy_id: Option<u64>,
+    ) -> Result<()> {
+        let keypair_wrapped = self.keyring.wrap(keypair)?;
+        let query_str = "INSERT INTO report_keypair (key_id, keypair, polid ) VALUES(?, ?, ?)";
+        let new_query_str = self.replace_binds(query_str);
+        sqlx::query(&new_query_str)
+            .bind(id)
+            .bind(&keypair_wrapped)
+            .bind(policy_id.map(|p| p as i64))
+            .execute(&mut *self.tx)
+            .await?;
+        Ok(())
+    }
+
+    // Delete every row of the five datastore tables, referencing tables first
+    // so foreign keys (when enforced) never block the wipe. Used by `import` to
+    // make a restore a full, idempotent replacement.
+    async fn clear_datastore(&mut self) -> Result<()> {
+        for table in ["secrets", "keysets", "report_keypair", "conn_bundle", "policy"] {
+            sqlx::query(&format!("DELETE FROM {}", table))
+                .execute(&mut *self.tx)
+                .await?;
+        }
+        Ok(())
+    }
+
+    // Reinsert a policy row verbatim (preserving create_date/valid) and return
+    // its freshly assigned id so referencing rows can be remapped.
+    async fn insert_policy_row(&mut self, row: &PolicyRow) -> Result<i64> {
+        let query_str = String::from("INSERT INTO policy (allowed_digests, allowed_policies, min_fw_api_major, min_fw_api_minor, allowed_build_ids, create_date, valid) VALUES(?, ?, ?, ?, ?, ?, ?)");
+
+        if self.kind == AnyKind::MySql || self.kind == AnyKind::Sqlite {
+            let last_insert_row = sqlx::query(&query_str)
+                .bind(&row.allowed_digests)
+                .bind(&row.allowed_policies)
+                .bind(row.min_fw_api_major)
+                .bind(row.min_fw_api_minor)
+                .bind(&row.allowed_build_ids)
+                .bind(&row.create_date)
+                .bind(row.valid)
+                .execute(&mut *self.tx)
+                .await?
+                .last_insert_id();
+            last_insert_row
+                .map(|p| p as i64)
+                .ok_or_else(|| anyhow!("db::insert_policy_row- last_insert_id() returned None"))
+        } else {
+            let new_query_str = self.replace_binds(&format!("{} RETURNING id", query_str));
+            let inserted = sqlx::query(&new_query_str)
+                .bind(&row.allowed_digests)
+                .bind(&row.allowed_policies)
+                .bind(row.min_fw_api_major)
+                .bind(row.min_fw_api_minor)
+                .bind(&row.allowed_build_ids)
+                .bind(&row.create_date)
+                .bind(row.valid)
+                .fetch_one(&mut *self.tx)
+                .await?;
+            Ok(inserted.try_get::<i32, _>(0)? as i64)
+        }
+    }
+
+    async fn insert_connection_row(&mut self, row: &ConnectionRow) -> Result<()> {
+        let query_str = "INSERT INTO conn_bundle (id, policy, fw_api_major, fw_api_minor, fw_build_id, launch_description, fw_digest, symkey, create_date) VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?)";
+        let new_query_str = self.replace_binds(query_str);
+        sqlx::query(&new_query_str)
+            .bind(&row.id)
+            .bind(row.policy)
+            .bind(row.fw_api_major)
+            .bind(row.fw_api_minor)
+            .bind(row.fw_build_id)
+            .bind(&row.launch_description)
+            .bind(&row.fw_digest)
+            .bind(&row.symkey)
+            .bind(&row.create_date)
+            .execute(&mut *self.tx)
+            .await?;
+        Ok(())
+    }
+
+    // Insert a secret binding its already-wrapped column value verbatim (no
+    // re-wrapping), with the remapped policy id.
+    async fn insert_secret_row(
+        &mut self,
+        secret_id: &str,
+        secret: &str,
+        polid: Option<i64>,
+    ) -> Result<()> {
+        let query_str = "INSERT INTO secrets (secret_id, secret, polid ) VALUES(?, ?, ?)";
+        let new_query_str = self.replace_binds(query_str);
+        sqlx::query(&new_query_str)
+            .bind(secret_id)
+            .bind(secret)
+            .bind(polid)
+            .execute(&mut *self.tx)
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_keyset_row(
+        &mut self,
+        keysetid: &str,
+        kskeys: &str,
+        polid: Option<i64>,
+    ) -> Result<()> {
+        let query_str = "INSERT INTO keysets (keysetid, kskeys, polid) VALUES(?, ?, ?)";
+        let new_query_str = self.replace_binds(query_str);
+        sqlx::query(&new_query_str)
+            .bind(keysetid)
+            .bind(kskeys)
+            .bind(polid)
+            .execute(&mut *self.tx)
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_report_keypair_row(
+        &mut self,
+        key_id: &str,
+        keypair: &str,
+        polid: Option<i64>,
+    ) -> Result<()> {
+        let query_str = "INSERT INTO report_keypair (key_id, keypair, polid ) VALUES(?, ?, ?)";
+        let new_query_str = self.replace_binds(query_str);
+        sqlx::query(&new_query_str)
+            .bind(key_id)
+            .bind(keypair)
+            .bind(polid)
+            .execute(&mut *self.tx)
+            .await?;
+        Ok(())
+    }
+
+    // Commit the batched writes. Consumes the guard.
+    pub async fn commit(self) -> Result<()> {
+        self.tx.commit().await?;
+        Ok(())
+    }
+
+    // Discard the batched writes. Consumes the guard; this is also what a
+    // dropped-without-commit guard does implicitly.
+    pub async fn rollback(self) -> Result<()> {
+        self.tx.rollback().await?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -448,6 +1246,9 @@ mod tests {
             min_fw_api_major: 0,
             min_fw_api_minor: 0,
             allowed_build_ids: vec![0u32, 1u32, 2u32],
+            allowed_dice_roots: vec![],
+            allowed_code_hashes: vec![],
+            min_security_version: None,
         };
 
         let polid = db.insert_policy(&testpol).await?;
@@ -496,6 +1297,9 @@ mod tests {
             min_fw_api_major: 23,
             min_fw_api_minor: 0,
             allowed_build_ids: vec![0u32, 1u32, 2u32],
+            allowed_dice_roots: vec![],
+            allowed_code_hashes: vec![],
+            min_security_version: None,
         };
 
         let tpid = db.insert_policy(&tinspol).await?;
@@ -506,7 +1310,10 @@ mod tests {
         db.insert_secret(&secid_uuid, &sec_uuid, Option::Some(tpid))
             .await?;
 
-        let testpol = db.get_secret_policy(&secid_uuid).await?;
+        let testpol = db
+            .get_secret_policy(&secid_uuid)
+            .await?
+            .expect("secret should have a policy");
 
         assert_eq!(
             testpol.allowed_digests[0],
@@ -603,6 +1410,9 @@ mod tests {
             min_fw_api_major: 0,
             min_fw_api_minor: 0,
             allowed_build_ids: vec![0u32, 1u32, 2u32],
+            allowed_dice_roots: vec![],
+            allowed_code_hashes: vec![],
+            min_security_version: None,
         };
 
         let polid = db.insert_policy(&testpol).await?;
@@ -645,4 +1455,90 @@ mod tests {
         }
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_transaction_commit() -> anyhow::Result<()> {
+        let db = KbsDb::new().await?;
+        let testpol = policy::Policy {
+            allowed_digests: vec!["0".to_string(), "1".to_string(), "3".to_string()],
+            allowed_policies: vec![0u32, 1u32, 2u32],
+            min_fw_api_major: 0,
+            min_fw_api_minor: 0,
+            allowed_build_ids: vec![0u32, 1u32, 2u32],
+            allowed_dice_roots: vec![],
+            allowed_code_hashes: vec![],
+            min_security_version: None,
+        };
+
+        let secid = Uuid::new_v4().as_hyphenated().to_string();
+        let sec = Uuid::new_v4().as_hyphenated().to_string();
+
+        // Policy and the secret referencing it are inserted atomically.
+        let mut tx = db.transaction().await?;
+        let polid = tx.insert_policy(&testpol).await?;
+        tx.insert_secret(&secid, &sec, Some(polid)).await?;
+        tx.commit().await?;
+
+        let tkey = db.get_secret(&secid).await?;
+        assert_eq!(tkey.payload, sec);
+
+        db.delete_secret(&secid).await?;
+        db.delete_policy(polid).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_backup_roundtrip() -> anyhow::Result<()> {
+        let db = KbsDb::new().await?;
+        let testpol = policy::Policy {
+            allowed_digests: vec!["digest-a".to_string()],
+            allowed_policies: vec![0u32, 1u32],
+            min_fw_api_major: 5,
+            min_fw_api_minor: 2,
+            allowed_build_ids: vec![7u32],
+            allowed_dice_roots: vec![],
+            allowed_code_hashes: vec![],
+            min_security_version: None,
+        };
+        let polid = db.insert_policy(&testpol).await?;
+        let secid = Uuid::new_v4().as_hyphenated().to_string();
+        let sec = Uuid::new_v4().as_hyphenated().to_string();
+        db.insert_secret(&secid, &sec, Some(polid)).await?;
+
+        // Snapshot, then restore onto the same store. Restore clears and
+        // reloads, so running it twice must leave the secret readable and still
+        // linked to an equivalent policy.
+        let backup = db.export().await?;
+        db.import(&backup).await?;
+        db.import(&backup).await?;
+
+        let tkey = db.get_secret(&secid).await?;
+        assert_eq!(tkey.payload, sec);
+
+        let resolved = db
+            .get_secret_policy(&secid)
+            .await?
+            .expect("imported secret should have a policy");
+        assert_eq!(resolved.allowed_digests, testpol.allowed_digests);
+        assert_eq!(resolved.min_fw_api_major, testpol.min_fw_api_major);
+
+        db.delete_secret(&secid).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transaction_rollback() -> anyhow::Result<()> {
+        let db = KbsDb::new().await?;
+        let secid = Uuid::new_v4().as_hyphenated().to_string();
+        let sec = Uuid::new_v4().as_hyphenated().to_string();
+
+        let mut tx = db.transaction().await?;
+        tx.insert_secret(&secid, &sec, None).await?;
+        tx.rollback().await?;
+
+        // A rolled-back insert leaves no row behind.
+        let res = db.get_secret(&secid).await;
+        assert!(res.is_err());
+        Ok(())
+    }
 }
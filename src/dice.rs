@@ -0,0 +1,416 @@
+// Copyright (c) 2022 IBM
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// DICE / layered attestation certificate chain verification.
+//
+// Some platforms present a layered DICE certificate chain instead of (or in
+// addition to) the scalar firmware fields matched by `Policy`. Each layer is a
+// CBOR Web Token (CWT) wrapped in a COSE_Sign1 structure: its payload carries
+// the next layer's public key plus that layer's measurement claims (code hash,
+// config hash, security version), and each token is signed by the key certified
+// in the previous layer. The chain is rooted in a trusted UDS-derived key.
+//
+// `Policy::verify_identity` walks the chain, checks every signature, requires
+// the root to be trusted, and rejects a leaf whose code hash is not allow-listed
+// or whose security version is below the configured minimum (a downgrade).
+
+use anyhow::*;
+use ring::signature;
+
+use crate::db;
+use crate::policy::Policy;
+
+// Private-use CWT claim labels for the DICE measurement profile.
+const CLAIM_SUBJECT_PUBLIC_KEY: i64 = -4670551;
+const CLAIM_CODE_HASH: i64 = -4670545;
+const CLAIM_CONFIG_HASH: i64 = -4670546;
+const CLAIM_SECURITY_VERSION: i64 = -4670547;
+
+// Measurement claims extracted from a verified DICE layer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiceClaims {
+    pub code_hash: Vec<u8>,
+    pub config_hash: Vec<u8>,
+    pub security_version: u32,
+    // SEC1 uncompressed public key certified for the next layer.
+    pub subject_public_key: Vec<u8>,
+}
+
+impl DiceClaims {
+    // Fold the verified leaf measurement into the connection so the existing
+    // scalar digest/policy checks run against the attested code hash.
+    pub fn apply_to(&self, connection: &mut db::Connection) {
+        connection.fw_digest = base64::encode(&self.code_hash);
+    }
+}
+
+// Minimal CBOR value, covering only the types a CWT/COSE_Sign1 uses here.
+enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+}
+
+// Decode one CBOR item at the start of `buf`, returning it and the number of
+// bytes consumed.
+fn decode(buf: &[u8]) -> Result<(Value, usize)> {
+    if buf.is_empty() {
+        bail!("unexpected end of CBOR input");
+    }
+    let ib = buf[0];
+    let major = ib >> 5;
+    let (arg, mut pos) = decode_argument(buf)?;
+
+    match major {
+        0 => Ok((Value::Int(i64::try_from(arg)?), pos)),
+        1 => Ok((Value::Int(-1 - i64::try_from(arg)?), pos)),
+        2 | 3 => {
+            let len = arg as usize;
+            if buf.len() < pos + len {
+                bail!("truncated CBOR string");
+            }
+            let slice = buf[pos..pos + len].to_vec();
+            pos += len;
+            if major == 2 {
+                Ok((Value::Bytes(slice), pos))
+            } else {
+                Ok((Value::Text(String::from_utf8(slice)?), pos))
+            }
+        }
+        4 => {
+            let mut items = Vec::with_capacity(arg as usize);
+            for _ in 0..arg {
+                let (v, used) = decode(&buf[pos..])?;
+                items.push(v);
+                pos += used;
+            }
+            Ok((Value::Array(items), pos))
+        }
+        5 => {
+            let mut pairs = Vec::with_capacity(arg as usize);
+            for _ in 0..arg {
+                let (k, used) = decode(&buf[pos..])?;
+                pos += used;
+                let (v, used) = decode(&buf[pos..])?;
+                pos += used;
+                pairs.push((k, v));
+            }
+            Ok((Value::Map(pairs), pos))
+        }
+        _ => bail!("unsupported CBOR major type {}", major),
+    }
+}
+
+// Read the argument (length/value) of a CBOR head, returning it and the
+// position just past the head bytes.
+fn decode_argument(buf: &[u8]) -> Result<(u64, usize)> {
+    let short = buf[0] & 0x1f;
+    match short {
+        0..=23 => Ok((short as u64, 1)),
+        24 => Ok((*buf.get(1).ok_or_else(|| anyhow!("truncated CBOR"))? as u64, 2)),
+        25 => {
+            let b = buf.get(1..3).ok_or_else(|| anyhow!("truncated CBOR"))?;
+            Ok((u16::from_be_bytes([b[0], b[1]]) as u64, 3))
+        }
+        26 => {
+            let b = buf.get(1..5).ok_or_else(|| anyhow!("truncated CBOR"))?;
+            Ok((u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as u64, 5))
+        }
+        27 => {
+            let b = buf.get(1..9).ok_or_else(|| anyhow!("truncated CBOR"))?;
+            Ok((
+                u64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]),
+                9,
+            ))
+        }
+        _ => bail!("indefinite-length CBOR is not supported"),
+    }
+}
+
+// CBOR encoder helpers, used to reconstruct the canonical Sig_structure.
+fn enc_head(out: &mut Vec<u8>, major: u8, value: u64) {
+    let mt = major << 5;
+    if value < 24 {
+        out.push(mt | value as u8);
+    } else if value <= u8::MAX as u64 {
+        out.push(mt | 24);
+        out.push(value as u8);
+    } else if value <= u16::MAX as u64 {
+        out.push(mt | 25);
+        out.extend_from_slice(&(value as u16).to_be_bytes());
+    } else if value <= u32::MAX as u64 {
+        out.push(mt | 26);
+        out.extend_from_slice(&(value as u32).to_be_bytes());
+    } else {
+        out.push(mt | 27);
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+fn enc_bstr(out: &mut Vec<u8>, bytes: &[u8]) {
+    enc_head(out, 2, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn enc_tstr(out: &mut Vec<u8>, s: &str) {
+    enc_head(out, 3, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+// The COSE Sig_structure a COSE_Sign1 is signed over:
+// ["Signature1", protected_bstr, external_aad_bstr, payload_bstr].
+fn sig_structure(protected: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    enc_head(&mut out, 4, 4);
+    enc_tstr(&mut out, "Signature1");
+    enc_bstr(&mut out, protected);
+    enc_bstr(&mut out, &[]); // empty external_aad
+    enc_bstr(&mut out, payload);
+    out
+}
+
+// Verify a COSE_Sign1-wrapped CWT with `signer_key` (SEC1 uncompressed ES256
+// public key) and return the decoded CWT claim map.
+fn verify_sign1(token: &[u8], signer_key: &[u8]) -> Result<Vec<(Value, Value)>> {
+    let (value, _) = decode(token)?;
+    let elems = match value {
+        Value::Array(e) if e.len() == 4 => e,
+        _ => bail!("COSE_Sign1 must be a 4-element array"),
+    };
+
+    let mut it = elems.into_iter();
+    let protected = match it.next() {
+        Some(Value::Bytes(b)) => b,
+        _ => bail!("COSE_Sign1 protected header must be a bstr"),
+    };
+    let _unprotected = it.next();
+    let payload = match it.next() {
+        Some(Value::Bytes(b)) => b,
+        _ => bail!("COSE_Sign1 payload must be a bstr"),
+    };
+    let sig = match it.next() {
+        Some(Value::Bytes(b)) => b,
+        _ => bail!("COSE_Sign1 signature must be a bstr"),
+    };
+
+    let tbs = sig_structure(&protected, &payload);
+    let public_key =
+        signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, signer_key);
+    public_key
+        .verify(&tbs, &sig)
+        .map_err(|_| anyhow!("invalid DICE certificate signature"))?;
+
+    match decode(&payload)? {
+        (Value::Map(m), _) => Ok(m),
+        _ => bail!("CWT payload must be a map"),
+    }
+}
+
+fn claim_bytes(claims: &[(Value, Value)], label: i64) -> Result<Vec<u8>> {
+    for (k, v) in claims {
+        if let (Value::Int(l), Value::Bytes(b)) = (k, v) {
+            if *l == label {
+                return Ok(b.clone());
+            }
+        }
+    }
+    bail!("missing DICE claim {}", label)
+}
+
+fn claim_u32(claims: &[(Value, Value)], label: i64) -> Result<u32> {
+    for (k, v) in claims {
+        if let (Value::Int(l), Value::Int(n)) = (k, v) {
+            if *l == label {
+                return Ok(u32::try_from(*n)?);
+            }
+        }
+    }
+    bail!("missing DICE claim {}", label)
+}
+
+fn extract_claims(claims: &[(Value, Value)]) -> Result<DiceClaims> {
+    Ok(DiceClaims {
+        code_hash: claim_bytes(claims, CLAIM_CODE_HASH)?,
+        config_hash: claim_bytes(claims, CLAIM_CONFIG_HASH)?,
+        security_version: claim_u32(claims, CLAIM_SECURITY_VERSION)?,
+        subject_public_key: claim_bytes(claims, CLAIM_SUBJECT_PUBLIC_KEY)?,
+    })
+}
+
+impl Policy {
+    // Walk a DICE certificate chain (base64-encoded COSE_Sign1 CWTs, root
+    // first) and return the verified leaf claims. Fails if any signature is
+    // invalid, the chain does not root in `allowed_dice_roots`, the leaf code
+    // hash is not allow-listed, or the leaf security version is a downgrade.
+    pub fn verify_identity(&self, chain: &[String]) -> Result<DiceClaims> {
+        if chain.is_empty() {
+            bail!("empty DICE certificate chain");
+        }
+
+        let mut signer: Option<Vec<u8>> = None;
+        let mut leaf: Option<DiceClaims> = None;
+
+        for (i, cert_b64) in chain.iter().enumerate() {
+            let cert = base64::decode(cert_b64)?;
+
+            let claims = if i == 0 {
+                // The first token must verify against one of the trusted roots.
+                let mut verified = None;
+                for root_b64 in &self.allowed_dice_roots {
+                    let root = base64::decode(root_b64)?;
+                    if let Ok(c) = verify_sign1(&cert, &root) {
+                        verified = Some(c);
+                        break;
+                    }
+                }
+                verified.ok_or_else(|| {
+                    anyhow!("DICE chain does not terminate at a trusted root")
+                })?
+            } else {
+                verify_sign1(&cert, signer.as_ref().unwrap())?
+            };
+
+            let extracted = extract_claims(&claims)?;
+            signer = Some(extracted.subject_public_key.clone());
+            leaf = Some(extracted);
+        }
+
+        let leaf = leaf.unwrap();
+
+        if !self.allowed_code_hashes.is_empty()
+            && !self
+                .allowed_code_hashes
+                .contains(&base64::encode(&leaf.code_hash))
+        {
+            bail!("DICE leaf code hash not allowed");
+        }
+
+        if let Some(min) = self.min_security_version {
+            if leaf.security_version < min {
+                bail!(
+                    "DICE security version {} below minimum {}",
+                    leaf.security_version,
+                    min
+                );
+            }
+        }
+
+        Ok(leaf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair};
+
+    // Build a COSE_Sign1 CWT signed by `signing_key`, carrying the given leaf
+    // measurement claims and certifying `subject_public_key`.
+    fn make_cert(
+        signing_key: &EcdsaKeyPair,
+        rng: &SystemRandom,
+        subject_public_key: &[u8],
+        code_hash: &[u8],
+        config_hash: &[u8],
+        security_version: u32,
+    ) -> String {
+        // Payload: CWT claim map.
+        let mut payload = Vec::new();
+        enc_head(&mut payload, 5, 4);
+        enc_head(&mut payload, 1, (-1 - CLAIM_SUBJECT_PUBLIC_KEY) as u64);
+        enc_bstr(&mut payload, subject_public_key);
+        enc_head(&mut payload, 1, (-1 - CLAIM_CODE_HASH) as u64);
+        enc_bstr(&mut payload, code_hash);
+        enc_head(&mut payload, 1, (-1 - CLAIM_CONFIG_HASH) as u64);
+        enc_bstr(&mut payload, config_hash);
+        enc_head(&mut payload, 1, (-1 - CLAIM_SECURITY_VERSION) as u64);
+        enc_head(&mut payload, 0, security_version as u64);
+
+        // Protected header: {1: -7} (ES256).
+        let mut protected = Vec::new();
+        enc_head(&mut protected, 5, 1);
+        enc_head(&mut protected, 0, 1);
+        enc_head(&mut protected, 1, 6); // -7
+
+        let tbs = sig_structure(&protected, &payload);
+        let sig = signing_key.sign(rng, &tbs).unwrap();
+
+        let mut cose = Vec::new();
+        enc_head(&mut cose, 4, 4);
+        enc_bstr(&mut cose, &protected);
+        enc_head(&mut cose, 5, 0); // empty unprotected map
+        enc_bstr(&mut cose, &payload);
+        enc_bstr(&mut cose, sig.as_ref());
+
+        base64::encode(cose)
+    }
+
+    fn keypair(rng: &SystemRandom) -> EcdsaKeyPair {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, rng).unwrap();
+        EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref())
+            .unwrap()
+    }
+
+    fn policy_with_root(root: &[u8]) -> Policy {
+        Policy {
+            allowed_digests: vec![],
+            allowed_policies: vec![],
+            min_fw_api_major: 0,
+            min_fw_api_minor: 0,
+            allowed_build_ids: vec![],
+            allowed_dice_roots: vec![base64::encode(root)],
+            allowed_code_hashes: vec![],
+            min_security_version: Some(2),
+        }
+    }
+
+    #[test]
+    fn test_verify_identity_chain() {
+        let rng = SystemRandom::new();
+        let root = keypair(&rng);
+        let leaf = keypair(&rng);
+
+        let root_pub = root.public_key().as_ref();
+        let leaf_pub = leaf.public_key().as_ref();
+
+        let cert = make_cert(&root, &rng, leaf_pub, &[0xaa; 32], &[0xbb; 32], 3);
+
+        let policy = policy_with_root(root_pub);
+        let claims = policy.verify_identity(&[cert]).unwrap();
+        assert_eq!(claims.code_hash, vec![0xaa; 32]);
+        assert_eq!(claims.security_version, 3);
+        assert_eq!(claims.subject_public_key, leaf_pub);
+    }
+
+    #[test]
+    fn test_untrusted_root_rejected() {
+        let rng = SystemRandom::new();
+        let root = keypair(&rng);
+        let other = keypair(&rng);
+        let leaf = keypair(&rng);
+
+        let cert = make_cert(&root, &rng, leaf.public_key().as_ref(), &[0xaa; 32], &[0xbb; 32], 3);
+
+        // Policy only trusts `other`, not the key that signed the cert.
+        let policy = policy_with_root(other.public_key().as_ref());
+        assert!(policy.verify_identity(&[cert]).is_err());
+    }
+
+    #[test]
+    fn test_security_version_downgrade_rejected() {
+        let rng = SystemRandom::new();
+        let root = keypair(&rng);
+        let leaf = keypair(&rng);
+
+        // security_version 1 is below the policy minimum of 2.
+        let cert = make_cert(&root, &rng, leaf.public_key().as_ref(), &[0xaa; 32], &[0xbb; 32], 1);
+
+        let policy = policy_with_root(root.public_key().as_ref());
+        assert!(policy.verify_identity(&[cert]).is_err());
+    }
+}
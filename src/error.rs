@@ -0,0 +1,112 @@
+// Copyright (c) 2022 IBM
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Typed errors for the key broker service.
+//
+// The gRPC handlers used to wrap every failure in `Status::internal`, which
+// made it impossible for a client to tell a malformed request apart from a
+// policy rejection or a genuine server fault. `KbsError` classifies the
+// failures we can produce and maps each one onto the appropriate gRPC status
+// code so callers can retry transient faults and treat attestation rejections
+// as terminal.
+
+use std::fmt;
+
+use tonic::{Code, Status};
+
+// Which policy check rejected the connection. Surfaced to the client so an
+// operator can tell whether the measurement, the guest policy bits, the
+// firmware api version or the build id was at fault.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyCheck {
+    Digest,
+    Policy,
+    FwApi,
+    BuildId,
+    Dice,
+}
+
+impl fmt::Display for PolicyCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            PolicyCheck::Digest => "fw digest not valid",
+            PolicyCheck::Policy => "policy not valid",
+            PolicyCheck::FwApi => "fw api version not valid",
+            PolicyCheck::BuildId => "build id not valid",
+            PolicyCheck::Dice => "dice identity not valid",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug)]
+pub enum KbsError {
+    // The request could not be parsed or carried an invalid field.
+    MalformedRequest(String),
+    // No pending launch session matched the supplied launch id.
+    LaunchIdNotFound(String),
+    // A policy check rejected the attested connection.
+    PolicyRejected(PolicyCheck),
+    // The launch measurement did not match the attested parameters.
+    MeasurementMismatch(String),
+    // The requested secret could not be produced or retrieved.
+    SecretUnavailable(String),
+    // Too many launch sessions are pending; the broker is at capacity.
+    TooManyPendingLaunches,
+    // An unexpected server-side fault.
+    Internal(String),
+}
+
+impl KbsError {
+    // A fatal error will not succeed on retry with the same request; the
+    // client should give up rather than reconnect.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            KbsError::MalformedRequest(_)
+                | KbsError::LaunchIdNotFound(_)
+                | KbsError::PolicyRejected(_)
+                | KbsError::MeasurementMismatch(_)
+        )
+    }
+
+    // A retryable error reflects transient or recoverable state (an
+    // unavailable backend, an internal hiccup) and may succeed on a fresh
+    // attempt. A missing launch session is treated as fatal, since the
+    // one-shot session cannot be recovered without a new get_bundle.
+    pub fn is_retryable(&self) -> bool {
+        !self.is_fatal()
+    }
+}
+
+impl fmt::Display for KbsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KbsError::MalformedRequest(m) => write!(f, "malformed request: {}", m),
+            KbsError::LaunchIdNotFound(id) => write!(f, "launch id not found: {}", id),
+            KbsError::PolicyRejected(c) => write!(f, "policy rejected: {}", c),
+            KbsError::MeasurementMismatch(m) => write!(f, "measurement mismatch: {}", m),
+            KbsError::SecretUnavailable(m) => write!(f, "secret unavailable: {}", m),
+            KbsError::TooManyPendingLaunches => write!(f, "too many pending launches"),
+            KbsError::Internal(m) => write!(f, "internal error: {}", m),
+        }
+    }
+}
+
+impl std::error::Error for KbsError {}
+
+impl From<KbsError> for Status {
+    fn from(e: KbsError) -> Status {
+        let code = match &e {
+            KbsError::MalformedRequest(_) => Code::InvalidArgument,
+            KbsError::LaunchIdNotFound(_) => Code::NotFound,
+            KbsError::PolicyRejected(_) => Code::PermissionDenied,
+            KbsError::MeasurementMismatch(_) => Code::FailedPrecondition,
+            KbsError::SecretUnavailable(_) => Code::NotFound,
+            KbsError::TooManyPendingLaunches => Code::ResourceExhausted,
+            KbsError::Internal(_) => Code::Internal,
+        };
+        Status::new(code, e.to_string())
+    }
+}
@@ -5,27 +5,63 @@
 
 use anyhow::*;
 use log::*;
-use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 use tonic::{transport::Server, Request, Response, Status};
 use uuid::Uuid;
 
 extern crate lazy_static;
 
+use crate::crypto;
 use crate::db;
+use crate::error::{KbsError, PolicyCheck};
 use crate::request;
+use crate::storage::DbStorage;
 use crate::sev_tools::{generate_launch_bundle, package_secret, verify_measurement};
 
+use rand::Rng;
+
 use sev::session::{Initialized, Session};
 
+mod session_store;
+use session_store::SessionStore;
+
 use key_broker::key_broker_service_server::{KeyBrokerService, KeyBrokerServiceServer};
 use key_broker::{BundleRequest, BundleResponse, SecretRequest, SecretResponse};
 
-// Keep the session for each connection in memory.
+// Defaults applied until the CLI configures the store in start_service.
+const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(600);
+const DEFAULT_MAX_PENDING_LAUNCHES: usize = 4096;
+const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Length of the content-encryption key minted for a COSE_Encrypt0 response.
+const COSE_CEK_LENGTH: usize = 32;
+
+// Keep the session for each connection in memory, bounded by a TTL and a cap.
 lazy_static::lazy_static! {
-    pub static ref SESSIONS: Arc<Mutex<HashMap<Uuid,Session<Initialized>>>> = Arc::new(Mutex::new(HashMap::new()));
+    pub static ref SESSIONS: Arc<Mutex<SessionStore<Session<Initialized>>>> = Arc::new(Mutex::new(
+        SessionStore::new(DEFAULT_SESSION_TTL, DEFAULT_MAX_PENDING_LAUNCHES)
+    ));
+}
+
+// Runtime tuning for the pending-launch session store.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionConfig {
+    pub ttl: Duration,
+    pub max_pending: usize,
+    pub sweep_interval: Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            ttl: DEFAULT_SESSION_TTL,
+            max_pending: DEFAULT_MAX_PENDING_LAUNCHES,
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+        }
+    }
 }
 
 pub mod key_broker {
@@ -47,10 +83,14 @@ impl KeyBrokerService for KeyBroker {
 
         // validate certificate chain
         let (godh, launch_blob, session) = generate_launch_bundle(r.policy, r.certificate_chain)
-            .map_err(|e| Status::internal(format!("Failed to generate launch bundle: {}", e)))?;
+            .map_err(|e| KbsError::Internal(format!("Failed to generate launch bundle: {}", e)))?;
 
-        let launch_id = Ok(Uuid::new_v4()).unwrap();
-        SESSIONS.lock().unwrap().insert(launch_id, session);
+        let launch_id = Uuid::new_v4();
+        SESSIONS
+            .lock()
+            .unwrap()
+            .insert(launch_id, session)
+            .map_err(|_| KbsError::TooManyPendingLaunches)?;
 
         let reply = BundleResponse {
             guest_owner_public_key: godh,
@@ -70,10 +110,13 @@ impl KeyBrokerService for KeyBroker {
         // Get connection from DB using connection ID
         let r = request.into_inner();
         let launch_id = Uuid::parse_str(&r.launch_id)
-            .map_err(|e| Status::internal(format!("Malformed Launch ID: {}", e)))?;
+            .map_err(|e| KbsError::MalformedRequest(format!("Malformed Launch ID: {}", e)))?;
+
+        let format = crypto::SecretFormat::parse(&r.format)
+            .map_err(|e| KbsError::MalformedRequest(format!("Bad secret format: {}", e)))?;
 
         // keep track of the connection
-        let connection = db::Connection {
+        let mut connection = db::Connection {
             policy: r.policy,
             fw_api_major: r.api_major,
             fw_api_minor: r.api_minor,
@@ -86,45 +129,104 @@ impl KeyBrokerService for KeyBroker {
 
         secret_request
             .parse_requests(&r.secret_requests)
-            .map_err(|e| Status::internal(format!("Bad secret request: {}", e)))?;
-
-        let policies = secret_request.policies();
+            .map_err(|e| KbsError::MalformedRequest(format!("Bad secret request: {}", e)))?;
+
+        let policies = secret_request.policies().await;
+
+        // Verify the guest's DICE identity against every policy that pins
+        // trusted roots, folding the attested leaf measurement into the
+        // connection before the scalar policy checks run against it. A policy
+        // that pins roots but receives no chain is rejected rather than
+        // silently passed: verify_identity fails on an empty chain.
+        for p in &policies {
+            if p.allowed_dice_roots.is_empty() {
+                continue;
+            }
+            let claims = p.verify_identity(&r.dice_chain).map_err(|e| {
+                warn!("DICE identity verification failed: {}", e);
+                KbsError::PolicyRejected(PolicyCheck::Dice)
+            })?;
+            claims.apply_to(&mut connection);
+        }
 
         // Validate connection against policies
-        for p in policies {
-            p.verify(&connection)
-                .map_err(|e| Status::internal(format!("Policy validation failed: {}", e)))?;
+        for p in &policies {
+            p.verify(&connection).map_err(KbsError::PolicyRejected)?;
         }
         info!(
             "Policy validated succesfully. Connection: {:?}",
             &connection
         );
 
-        let session = SESSIONS.lock().unwrap().remove(&launch_id).ok_or_else(|| {
-            Status::internal(format!("Launch ID not found. UUID: {}", &launch_id))
-        })?;
+        let session = SESSIONS
+            .lock()
+            .unwrap()
+            .remove(&launch_id)
+            .ok_or_else(|| KbsError::LaunchIdNotFound(launch_id.to_string()))?;
 
         // verify launch measurement
         let session_verified = verify_measurement(&connection, r.launch_measurement, session)
-            .map_err(|e| Status::internal(format!("Measurement Verification Failed: {}", e)))?;
-
-        // get secret(s)
-        let secret_payload = &secret_request
-            .payload(&connection)
-            .map_err(|e| Status::internal(format!("Cannot fulfill secret request: {}", e)))?;
-
-        let (secret_header, secret_data) = package_secret(session_verified, secret_payload)
-            .map_err(|e| Status::internal(format!("Failed to package secret: {}", e)))?;
-
-        let reply = SecretResponse {
-            launch_secret_header: secret_header,
-            launch_secret_data: secret_data,
+            .map_err(|e| KbsError::MeasurementMismatch(e.to_string()))?;
+
+        // get secret(s); the policy gate is enforced inside the storage layer
+        let storage = DbStorage;
+        let secret_payload = secret_request
+            .payload(&connection, &storage)
+            .await
+            .map_err(|e| KbsError::SecretUnavailable(e.to_string()))?;
+
+        let reply = match format {
+            // The raw secret is injected over SEV, which seals it under this
+            // launch's transport key, so it cannot be replayed against another.
+            crypto::SecretFormat::Raw => {
+                let (secret_header, secret_data) = package_secret(
+                    session_verified,
+                    &secret_payload,
+                )
+                .map_err(|e| KbsError::Internal(format!("Failed to package secret: {}", e)))?;
+                SecretResponse {
+                    launch_secret_header: secret_header,
+                    launch_secret_data: secret_data,
+                    secret_format: "raw".to_string(),
+                    secret_cose: String::new(),
+                }
+            }
+            // Encrypt the payload as COSE_Encrypt0 bound to the attested
+            // connection, and inject the content-encryption key over SEV so only
+            // this guest can open it with a standard COSE library.
+            crypto::SecretFormat::Cose => {
+                // Bind the COSE ciphertext to the attested connection via its
+                // GCM AAD so it cannot be replayed against a different launch.
+                let connection_aad = crypto::connection_aad(&connection);
+                let cek = rand::thread_rng().gen::<[u8; COSE_CEK_LENGTH]>();
+                let secret_cose = crypto::encrypt_secret_payload_cose(
+                    &secret_payload,
+                    base64::encode(cek),
+                    &connection_aad,
+                )
+                .map_err(|e| KbsError::Internal(format!("Failed to encode COSE secret: {}", e)))?;
+                let (secret_header, secret_data) = package_secret(session_verified, &cek)
+                    .map_err(|e| KbsError::Internal(format!("Failed to package key: {}", e)))?;
+                SecretResponse {
+                    launch_secret_header: secret_header,
+                    launch_secret_data: secret_data,
+                    secret_format: "cose".to_string(),
+                    secret_cose,
+                }
+            }
         };
         Result::Ok(Response::new(reply))
     }
 }
 
-pub async fn start_service(socket: SocketAddr) -> Result<()> {
+pub async fn start_service(socket: SocketAddr, config: SessionConfig) -> Result<()> {
+    // Apply the CLI-supplied tuning and start the background TTL sweeper.
+    SESSIONS
+        .lock()
+        .unwrap()
+        .configure(config.ttl, config.max_pending);
+    spawn_session_sweeper(config.sweep_interval);
+
     let service = KeyBroker::default();
     let _server = Server::builder()
         .add_service(KeyBrokerServiceServer::new(service))
@@ -132,3 +234,18 @@ pub async fn start_service(socket: SocketAddr) -> Result<()> {
         .await?;
     Ok(())
 }
+
+// Periodically evict pending launch sessions that have outlived their TTL, so a
+// guest that never completes get_secret cannot grow the map without bound.
+fn spawn_session_sweeper(interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let evicted = SESSIONS.lock().unwrap().evict_expired();
+            if evicted > 0 {
+                debug!("Evicted {} expired launch session(s)", evicted);
+            }
+        }
+    });
+}
@@ -0,0 +1,128 @@
+// Copyright (c) 2022 IBM
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Bounded, self-expiring store for pending launch sessions.
+//
+// A guest that calls get_bundle but never completes get_secret used to leak
+// its session forever, since the map only shrank on a successful get_secret.
+// `SessionStore` stamps each entry with its insertion time, enforces a
+// max-entry cap on insert, and evicts entries older than a TTL (swept
+// periodically by a background task spawned in start_service).
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+// Returned by `insert` when the store is already holding `max_entries`
+// pending launches.
+#[derive(Debug)]
+pub struct AtCapacity;
+
+struct Entry<T> {
+    value: T,
+    inserted: Instant,
+}
+
+pub struct SessionStore<T> {
+    entries: HashMap<Uuid, Entry<T>>,
+    ttl: Duration,
+    max_entries: usize,
+}
+
+impl<T> SessionStore<T> {
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        SessionStore {
+            entries: HashMap::new(),
+            ttl,
+            max_entries,
+        }
+    }
+
+    // Apply runtime configuration once the CLI has been parsed.
+    pub fn configure(&mut self, ttl: Duration, max_entries: usize) {
+        self.ttl = ttl;
+        self.max_entries = max_entries;
+    }
+
+    // Insert a pending session, rejecting the insert if the store is full.
+    // Expired entries are reclaimed first so a full store of stale sessions
+    // does not wrongly reject a fresh launch.
+    pub fn insert(&mut self, id: Uuid, value: T) -> Result<(), AtCapacity> {
+        self.evict_expired();
+        if self.entries.len() >= self.max_entries {
+            return Err(AtCapacity);
+        }
+        self.entries.insert(
+            id,
+            Entry {
+                value,
+                inserted: Instant::now(),
+            },
+        );
+        Ok(())
+    }
+
+    // Remove and return a session by id, if present.
+    pub fn remove(&mut self, id: &Uuid) -> Option<T> {
+        self.entries.remove(id).map(|e| e.value)
+    }
+
+    // Drop every entry older than the TTL, returning how many were evicted.
+    pub fn evict_expired(&mut self) -> usize {
+        let ttl = self.ttl;
+        let before = self.entries.len();
+        self.entries.retain(|_, e| e.inserted.elapsed() < ttl);
+        before - self.entries.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    #[test]
+    fn test_expiry() {
+        let mut store: SessionStore<u32> = SessionStore::new(Duration::from_millis(50), 16);
+        store.insert(Uuid::new_v4(), 1).unwrap();
+        assert_eq!(store.len(), 1);
+
+        sleep(Duration::from_millis(80));
+        let evicted = store.evict_expired();
+        assert_eq!(evicted, 1);
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_capacity_rejects_insert() {
+        let mut store: SessionStore<u32> = SessionStore::new(Duration::from_secs(60), 2);
+        store.insert(Uuid::new_v4(), 1).unwrap();
+        store.insert(Uuid::new_v4(), 2).unwrap();
+
+        // Third insert is rejected while the first two are still live.
+        assert!(store.insert(Uuid::new_v4(), 3).is_err());
+        assert_eq!(store.len(), 2);
+    }
+
+    #[test]
+    fn test_capacity_reclaims_expired() {
+        let mut store: SessionStore<u32> = SessionStore::new(Duration::from_millis(50), 1);
+        store.insert(Uuid::new_v4(), 1).unwrap();
+        assert!(store.insert(Uuid::new_v4(), 2).is_err());
+
+        // Once the first entry expires, a fresh launch can take its slot.
+        sleep(Duration::from_millis(80));
+        assert!(store.insert(Uuid::new_v4(), 3).is_ok());
+        assert_eq!(store.len(), 1);
+    }
+}
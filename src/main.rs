@@ -7,12 +7,18 @@ use anyhow::*;
 use clap::{Command, Arg};
 use log::*;
 use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::grpc::SessionConfig;
 
 pub mod db;
+pub mod dice;
+pub mod error;
 pub mod grpc;
 pub mod policy;
 pub mod request;
 pub mod sev_tools;
+pub mod storage;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -25,6 +31,18 @@ async fn main() -> Result<()> {
                 .takes_value(true)
                 .help("Socket that the server will listen on."),
         )
+        .arg(
+            Arg::new("session ttl")
+                .long("session_ttl")
+                .takes_value(true)
+                .help("Seconds a pending launch session is kept before eviction."),
+        )
+        .arg(
+            Arg::new("max pending launches")
+                .long("max_pending_launches")
+                .takes_value(true)
+                .help("Maximum number of pending launch sessions held in memory."),
+        )
         .get_matches();
 
     let socket = args
@@ -32,8 +50,24 @@ async fn main() -> Result<()> {
         .unwrap_or("127.0.0.1:44444")
         .parse::<SocketAddr>()?;
 
+    let mut session_config = SessionConfig::default();
+    if let Some(ttl) = args.value_of("session ttl") {
+        let ttl = ttl.parse::<u64>()?;
+        if ttl == 0 {
+            bail!("session_ttl must be greater than zero");
+        }
+        session_config.ttl = Duration::from_secs(ttl);
+    }
+    if let Some(max) = args.value_of("max pending launches") {
+        let max = max.parse::<usize>()?;
+        if max == 0 {
+            bail!("max_pending_launches must be greater than zero");
+        }
+        session_config.max_pending = max;
+    }
+
     info!("Starting gRPC Server");
-    grpc::start_service(socket).await?;
+    grpc::start_service(socket, session_config).await?;
 
     Ok(())
 }
@@ -4,6 +4,7 @@
 //
 
 use crate::db;
+use crate::error::PolicyCheck;
 use anyhow::*;
 use serde::Deserialize;
 use std::fs;
@@ -17,6 +18,15 @@ pub struct Policy {
     pub min_fw_api_major: u32,
     pub min_fw_api_minor: u32,
     pub allowed_build_ids: Vec<u32>,
+
+    // DICE identity checks. These are optional and default to empty, so
+    // policies that do not target a layered DICE platform are unaffected.
+    #[serde(default)]
+    pub allowed_dice_roots: Vec<String>,
+    #[serde(default)]
+    pub allowed_code_hashes: Vec<String>,
+    #[serde(default)]
+    pub min_security_version: Option<u32>,
 }
 
 impl Policy {
@@ -34,22 +44,24 @@ impl Policy {
 }
 
 impl Policy {
-    pub fn verify(&self, connection: &db::Connection) -> Result<()> {
+    // Verify a connection against the policy. On failure the rejecting check is
+    // returned so the caller can report an actionable reason to the client.
+    pub fn verify(&self, connection: &db::Connection) -> std::result::Result<(), PolicyCheck> {
         if !self.allowed_digests.is_empty()
             && !self
                 .allowed_digests
                 .contains(&connection.fw_digest.to_string())
         {
-            return Err(anyhow!("fw digest not valid"));
+            return Err(PolicyCheck::Digest);
         }
 
         if !self.allowed_policies.is_empty() && !self.allowed_policies.contains(&connection.policy)
         {
-            return Err(anyhow!("policy not valid"));
+            return Err(PolicyCheck::Policy);
         }
 
         if connection.fw_api_major < self.min_fw_api_major {
-            return Err(anyhow!("fw api major not valid"));
+            return Err(PolicyCheck::FwApi);
         }
 
         // if we have exactly the minimum required major version,
@@ -57,13 +69,13 @@ impl Policy {
         if connection.fw_api_major == self.min_fw_api_major
             && connection.fw_api_minor < self.min_fw_api_minor
         {
-            return Err(anyhow!("fw api minor not valid"));
+            return Err(PolicyCheck::FwApi);
         }
 
         if !self.allowed_build_ids.is_empty()
             && !self.allowed_build_ids.contains(&connection.fw_build_id)
         {
-            return Err(anyhow!("build id not valid"));
+            return Err(PolicyCheck::BuildId);
         }
 
         Ok(())
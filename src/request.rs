@@ -16,6 +16,7 @@ use uuid::Uuid;
 use crate::db;
 use crate::grpc::key_broker::RequestDetails;
 use crate::policy;
+use crate::storage::PolicyGatedStorage;
 
 // GUID that marks the beginning of the secret table
 const SECRET_GUID: Uuid = uuid::uuid!("1e74f542-71dd-4d66-963e-ef4287ff173b");
@@ -61,11 +62,15 @@ impl SecretRequest {
         policies
     }
 
-    pub async fn payload(&self, connection: &db::Connection) -> Result<Vec<u8>> {
+    pub async fn payload(
+        &self,
+        connection: &db::Connection,
+        storage: &dyn PolicyGatedStorage,
+    ) -> Result<Vec<u8>> {
         let mut payload = vec![];
 
         for s in &self.secrets {
-            let secret_payload = s.payload(connection.clone()).await?;
+            let secret_payload = s.payload(connection.clone(), storage).await?;
 
             payload.extend_from_slice(&Uuid::parse_str(s.guid()).unwrap().to_bytes_le());
             payload.extend_from_slice(
@@ -93,7 +98,11 @@ impl SecretRequest {
 
 #[async_trait]
 trait SecretType {
-    async fn payload(&self, connection: db::Connection) -> Result<Vec<u8>>;
+    async fn payload(
+        &self,
+        connection: db::Connection,
+        storage: &dyn PolicyGatedStorage,
+    ) -> Result<Vec<u8>>;
     async fn policies(&self) -> Vec<policy::Policy>;
     fn guid(&self) -> &String;
 }
@@ -104,19 +113,30 @@ struct SecretKey {
 
 #[async_trait]
 impl SecretType for SecretKey {
-    #[allow(unused_variables)]
-    async fn payload(&self, connection: db::Connection) -> Result<Vec<u8>> {
-        let key = db::get_secret(&self.request.id).await?;
+    async fn payload(
+        &self,
+        connection: db::Connection,
+        storage: &dyn PolicyGatedStorage,
+    ) -> Result<Vec<u8>> {
+        // The policy gate is enforced inside the storage layer.
+        let secret = storage.get(&self.request.id, &connection).await?;
         Ok(match &self.request.format[..] {
-            "binary" => key.into_bytes(),
-            "json" => serde_json::to_string(&key).unwrap().into_bytes(),
+            "binary" => secret,
+            "json" => {
+                let key = Key {
+                    id: self.request.id.clone(),
+                    payload: base64::encode(&secret),
+                };
+                serde_json::to_string(&key).unwrap().into_bytes()
+            }
             _ => return Err(anyhow!("Unknown format type")),
         })
     }
 
     async fn policies(&self) -> Vec<policy::Policy> {
         match db::get_secret_policy(&self.request.id).await {
-            Ok(policy) => vec![policy],
+            Ok(Some(policy)) => vec![policy],
+            Ok(None) => vec![],
             Err(e) => {
                 error!(
                     "Error getting policy for secret with id {}. Details: {}",
@@ -150,14 +170,17 @@ struct SecretBundle {
 
 #[async_trait]
 impl SecretType for SecretBundle {
-    #[allow(unused_variables)]
-    async fn payload(&self, connection: db::Connection) -> Result<Vec<u8>> {
+    async fn payload(
+        &self,
+        connection: db::Connection,
+        storage: &dyn PolicyGatedStorage,
+    ) -> Result<Vec<u8>> {
         let mut bundle = HashMap::new();
 
         let secrets = db::get_keyset_ids(&self.request.id).await?;
         for s in secrets {
-            let k = db::get_secret(&s).await?;
-            bundle.insert(k.id, k.payload);
+            let secret = storage.get(&s, &connection).await?;
+            bundle.insert(s, base64::encode(&secret));
         }
         Ok(serde_json::to_string(&bundle)?.into_bytes())
     }
@@ -181,7 +204,8 @@ impl SecretType for SecretBundle {
         if let Ok(secrets) = db::get_keyset_ids(&self.request.id).await {
             for s in secrets {
                 match db::get_secret_policy(&s).await {
-                    Ok(policy) => policies.push(policy),
+                    Ok(Some(policy)) => policies.push(policy),
+                    Ok(None) => {}
                     Err(e) => {
                         error!(
                             "Error getting policy for secret with id {}. Details: {}",
@@ -219,7 +243,11 @@ struct Report {
 
 #[async_trait]
 impl SecretType for SecretReport {
-    async fn payload(&self, connection: db::Connection) -> Result<Vec<u8>> {
+    async fn payload(
+        &self,
+        connection: db::Connection,
+        _storage: &dyn PolicyGatedStorage,
+    ) -> Result<Vec<u8>> {
         let rng = SystemRandom::new();
 
         let key_pair_pkcs8 = db::get_report_keypair(&self.request.id).await?;
@@ -277,7 +305,11 @@ struct ConnectionOutput {
 
 #[async_trait]
 impl SecretType for SecretConnection {
-    async fn payload(&self, connection: db::Connection) -> Result<Vec<u8>> {
+    async fn payload(
+        &self,
+        connection: db::Connection,
+        _storage: &dyn PolicyGatedStorage,
+    ) -> Result<Vec<u8>> {
         let (connection_id, key) = db::insert_connection(connection).await?;
         let output = ConnectionOutput { connection_id, key };
 
@@ -302,6 +334,7 @@ mod tests {
     use super::*;
     use crate::db;
     use crate::grpc::key_broker::RequestDetails;
+    use crate::storage::DbStorage;
     use ring::signature::KeyPair;
 
     #[tokio::test]
@@ -328,7 +361,7 @@ mod tests {
         let secret_key = SecretKey { request };
         assert!(secret_key.policies().await.is_empty());
         assert_eq!(secret_key.guid(), &guid);
-        assert_eq!(secret_bytes, secret_key.payload(connection).await.unwrap());
+        assert_eq!(secret_bytes, secret_key.payload(connection, &DbStorage).await.unwrap());
 
         db::delete_secret(&secret_id).await.unwrap();
     }
@@ -362,7 +395,7 @@ mod tests {
         let mut expected_payload = HashMap::new();
         expected_payload.insert(&secret_id, &secret_value);
         assert_eq!(
-            secret_bundle.payload(connection).await.unwrap(),
+            secret_bundle.payload(connection, &DbStorage).await.unwrap(),
             serde_json::to_string(&expected_payload)
                 .unwrap()
                 .into_bytes()
@@ -411,7 +444,7 @@ mod tests {
         assert!(r.policies().await.is_empty());
 
         // get report payload
-        let payload = r.payload(connection.clone()).await.unwrap();
+        let payload = r.payload(connection.clone(), &DbStorage).await.unwrap();
         let report: Report = serde_json::from_slice(&payload).unwrap();
 
         // make sure the connection in the report matches
@@ -460,7 +493,7 @@ mod tests {
         assert_eq!(policies.len(), 1);
         assert_eq!(policies[0], expected_policy);
 
-        let payload = secret_request.payload(&connection).await.unwrap();
+        let payload = secret_request.payload(&connection, &DbStorage).await.unwrap();
 
         #[repr(C)]
         #[derive(Serialize)]
@@ -60,6 +60,14 @@ pub fn verify_measurement(
 // All the functions in this file should take in what we get from
 // gRPC and return what we need in response
 // There should be no conversions in the other file
+//
+// The returned secret is injected with `Session<Verified>::secret`, which seals
+// it under the transport integrity key negotiated for *this* launch during
+// get_bundle/verify_measurement. A secret (or content-encryption key) packaged
+// for one launch therefore cannot be injected into another — the firmware
+// rejects a blob whose MAC does not match the target launch's key — so replay
+// across connections is prevented by the SEV protocol itself, without an
+// application-level AAD binding on the raw response.
 pub fn package_secret(session: Session<Verified>, secret: &[u8]) -> Result<(String, String)> {
     let secret = session.secret(HeaderFlags::default(), secret)?;
     let header = base64::encode(bincode::serialize(&secret.header)?);
@@ -0,0 +1,89 @@
+// Copyright (c) 2022 IBM
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Policy-gated secret storage.
+//
+// Secrets used to be fetched straight out of `db` after the gRPC handler had
+// (hopefully) already verified the connection against the secret's policy.
+// That left the policy gate dependent on call ordering. `PolicyGatedStorage`
+// moves the gate inside the storage layer: an implementation only returns the
+// secret material after `Policy::verify` succeeds for the supplied connection,
+// and new backends (a file/KV directory, an external KMS) can be added without
+// touching the handler.
+
+use anyhow::*;
+use async_trait::async_trait;
+use std::path::{Path, PathBuf};
+
+use crate::db;
+use crate::policy;
+
+#[async_trait]
+pub trait PolicyGatedStorage: Send + Sync {
+    // Return the secret identified by `id`, but only once `connection` has
+    // satisfied the policy attached to that secret.
+    async fn get(&self, id: &str, connection: &db::Connection) -> Result<Vec<u8>>;
+}
+
+// Enforce the secret's policy against the connection. A secret with no policy
+// is gated by the tenant default alone (applied by the request layer).
+fn gate(policy: &policy::Policy, connection: &db::Connection) -> Result<()> {
+    policy
+        .verify(connection)
+        .map_err(|c| anyhow!("policy rejected: {}", c))
+}
+
+// Backend reading secrets out of the SQL `db`.
+#[derive(Default)]
+pub struct DbStorage;
+
+#[async_trait]
+impl PolicyGatedStorage for DbStorage {
+    async fn get(&self, id: &str, connection: &db::Connection) -> Result<Vec<u8>> {
+        // A secret may legitimately carry no policy (Ok(None)); only then is the
+        // gate skipped. A lookup error is propagated so a transient backend
+        // failure fails closed rather than releasing the secret ungated.
+        if let Some(policy) = db::get_secret_policy(id).await? {
+            gate(&policy, connection)?;
+        }
+        let key = db::get_secret(id).await?;
+        Ok(key.into_bytes())
+    }
+}
+
+// Backend reading secrets out of a directory: `<dir>/<id>` holds the base64
+// secret and an optional `<dir>/<id>.policy.json` holds its policy.
+pub struct FileStorage {
+    root: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        FileStorage {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+}
+
+#[async_trait]
+impl PolicyGatedStorage for FileStorage {
+    async fn get(&self, id: &str, connection: &db::Connection) -> Result<Vec<u8>> {
+        // A genuinely absent policy file means the secret carries no policy; any
+        // other read error fails closed so we never release a secret ungated.
+        let policy_path = self.root.join(format!("{}.policy.json", id));
+        match tokio::fs::read_to_string(&policy_path).await {
+            Ok(policy_string) => {
+                let policy: policy::Policy = serde_json::from_str(&policy_string)?;
+                gate(&policy, connection)?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(anyhow!("failed to read policy for {}: {}", id, e)),
+        }
+
+        let secret_string = tokio::fs::read_to_string(self.root.join(id))
+            .await
+            .map_err(|e| anyhow!("secret {} not found: {}", id, e))?;
+        Ok(base64::decode(secret_string.trim())?)
+    }
+}